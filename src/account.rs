@@ -0,0 +1,166 @@
+use crate::{
+    connection::{Incoming, XmppConnection},
+    login::Login,
+    profile::Profile,
+    sm::StreamManagement,
+    AccountConfig,
+};
+use anyhow::Result;
+use die::{die, Die};
+use tokio_xmpp::{AsyncClient as Client, AsyncConfig, AsyncServerConfig};
+use xmpp_parsers::{BareJid, Element, Jid};
+
+// one of the accounts, or components, ash is connected as, everything else is shared between them
+pub struct Account {
+    pub jid: BareJid,
+    // jid, with the resource if one's configured, which tells accounts apart in the database
+    pub id: String,
+    // used in rooms that don't configure their own
+    pub nick: String,
+    pub connection: XmppConnection,
+    // between Incoming::Online and Incoming::Offline, nothing can be sent otherwise
+    pub online: bool,
+    pub sm: StreamManagement,
+    // connected as a XEP-0114 component rather than a client account
+    pub component: bool,
+    // join rooms bookmarked with autojoin in our PEP storage
+    pub bookmarks: bool,
+    // id of the bookmarks request still waiting on a result
+    pub bookmarks_request: Option<String>,
+    // avatar and vCard, published when they differ from what the server has
+    pub profile: Option<Profile>,
+    // the status we last sent, so it's only sent again when it changes
+    pub status_sent: String,
+}
+
+impl Account {
+    // dies on anything in cfg that can't work, the same way bad config is handled everywhere else
+    pub fn connect(cfg: &AccountConfig, nick: Option<&String>) -> Result<Self> {
+        let connection = cfg.connection.as_ref();
+        let jid: BareJid = match (&cfg.jid, &cfg.component_jid) {
+            (Some(jid), None) => jid.parse()?,
+            (None, Some(component_jid)) => component_jid.parse()?,
+            _ => die!("set one of jid or component_jid in each account"),
+        };
+        let component = cfg.component_jid.is_some();
+        let stream_management = connection
+            .and_then(|connection| connection.stream_management)
+            .unwrap_or(false);
+        if component {
+            // all of these are for accounts, which components don't have
+            if cfg.bookmarks.unwrap_or(false) || cfg.profile.is_some() {
+                die!("bookmarks and profile need a jid, not a component_jid");
+            }
+            if connection.map_or(false, |c| c.resource.is_some()) || stream_management {
+                die!("resource and stream_management need a jid, not a component_jid");
+            }
+            if Login::needed(connection) {
+                die!("direct_tls, ca_file, pin_sha256 and proxy need a jid, not a component_jid");
+            }
+        }
+        let resource = connection.and_then(|connection| connection.resource.as_ref());
+        let id = match resource {
+            Some(resource) => format!("{jid}/{resource}"),
+            None => jid.to_string(),
+        };
+        let host = connection.and_then(|connection| connection.host.clone());
+        let port = connection.and_then(|connection| connection.port);
+        let xmpp = if component {
+            let secret = cfg.secret.as_ref().die("component_jid needs a secret");
+            XmppConnection::component(
+                Jid::Bare(jid.clone()),
+                secret.clone(),
+                host.unwrap_or_else(|| "localhost".to_string()),
+                port.unwrap_or(5347),
+            )
+        } else if Login::needed(connection) || stream_management {
+            // stream management too, since tokio-xmpp can't resume streams
+            let password = cfg.password.as_ref().die("jid needs a password");
+            XmppConnection::login(Login::new(&jid, resource, password, connection)?)
+        } else {
+            let password = cfg.password.as_ref().die("jid needs a password");
+            let server = match (host, port) {
+                (None, None) => AsyncServerConfig::UseSrv,
+                (host, port) => AsyncServerConfig::Manual {
+                    host: host.unwrap_or_else(|| jid.domain.clone()),
+                    port: port.unwrap_or(5222),
+                },
+            };
+            let mut client = Client::new_with_config(AsyncConfig {
+                jid: match resource {
+                    Some(resource) => Jid::Full(jid.clone().with_resource(resource)),
+                    None => Jid::Bare(jid.clone()),
+                },
+                password: password.clone(),
+                server,
+            });
+            client.set_reconnect(true);
+            XmppConnection::Client(client)
+        };
+        Ok(Self {
+            nick: cfg
+                .nick
+                .as_ref()
+                .or(nick)
+                .or(jid.node.as_ref())
+                .map(|s| s.as_str())
+                .unwrap_or("ash")
+                .to_string(),
+            jid,
+            id,
+            connection: xmpp,
+            online: false,
+            sm: StreamManagement::new(stream_management),
+            component,
+            bookmarks: cfg.bookmarks.unwrap_or(false),
+            bookmarks_request: None,
+            profile: cfg
+                .profile
+                .as_ref()
+                .map(Profile::new)
+                .transpose()
+                .die("avatar cannot be read"),
+            status_sent: String::new(),
+        })
+    }
+
+    pub async fn next(&mut self) -> Incoming {
+        let incoming = self.connection.next().await;
+        match incoming {
+            Incoming::Online(_) => self.online = true,
+            Incoming::Offline => {
+                self.online = false;
+                self.connection.resume_with(self.sm.resumption());
+            }
+            Incoming::Stanza(_) => {}
+        }
+        incoming
+    }
+
+    // sends stanza, messages sent while disconnected are kept to send once we're back if stream
+    // management is on, and anything else is dropped, failures are only logged since one account
+    // losing its connection mustn't stop the others
+    pub async fn send(&mut self, stanza: Element) {
+        let request = self.sm.sent(&stanza);
+        if !self.online {
+            match request {
+                Some(_) => println!("{} is offline, will send after reconnecting", self.id),
+                None => println!("{} is offline, dropping a {}", self.id, stanza.name()),
+            }
+            return;
+        }
+        let name = stanza.name().to_string();
+        if let Err(e) = self.connection.send_stanza(stanza).await {
+            match request {
+                Some(_) => println!("failed to send, will try again after reconnecting: {e}"),
+                None => println!("{} failed to send a {name}: {e}", self.id),
+            }
+            return;
+        }
+        if let Some(request) = request {
+            if let Err(e) = self.connection.send_stanza(request).await {
+                println!("{} failed to ask for an acknowledgement: {e}", self.id);
+            }
+        }
+    }
+}
@@ -0,0 +1,134 @@
+use crate::{account::Account, bare, make_join, make_leave, Ash, RoomConfig};
+use xmpp_parsers::{
+    iq::{Iq, IqType},
+    message::Message,
+    ns, BareJid, Element, Jid,
+};
+
+pub const NS_BOOKMARKS: &str = "urn:xmpp:bookmarks:1";
+
+// Construct an <iq/> fetching every XEP-0402 bookmark from our own PEP service, with an id that
+// can't be guessed, remembered on account so the result can be told apart from other responses
+pub fn make_request(account: &mut Account) -> Element {
+    use rand::Rng;
+    let id = format!("ash-bookmarks-{:016x}", rand::thread_rng().gen::<u64>());
+    account.bookmarks_request = Some(id.clone());
+    Iq {
+        from: None,
+        to: None,
+        id,
+        payload: IqType::Get(
+            Element::builder("pubsub", ns::PUBSUB)
+                .append(
+                    Element::builder("items", ns::PUBSUB)
+                        .attr("node", NS_BOOKMARKS)
+                        .build(),
+                )
+                .build(),
+        ),
+    }
+    .into()
+}
+
+// whether an <iq/> result with id from from answers make_request, which only our own account can
+pub fn is_result(ash: &Ash, id: &str, from: Option<&Jid>) -> bool {
+    ash.account().bookmarks_request.as_deref() == Some(id)
+        && from.map_or(true, |from| bare(from) == ash.account().jid)
+}
+
+// joins every autojoin bookmark in the response to make_request, returns the joins to send
+pub fn handle_result(ash: &mut Ash, pubsub: &Element) -> Vec<Element> {
+    ash.accounts[ash.current].bookmarks_request = None;
+    pubsub
+        .get_child("items", ns::PUBSUB)
+        .into_iter()
+        .flat_map(|items| items.children())
+        .filter(|item| item.is("item", ns::PUBSUB))
+        .filter_map(|item| {
+            let conference = item.get_child("conference", NS_BOOKMARKS);
+            apply(ash, item.attr("id")?, conference)
+        })
+        .collect()
+}
+
+// joins or leaves rooms as bookmarks are published or retracted, returns the presences to send
+pub fn handle_event(ash: &mut Ash, message: &Message) -> Vec<Element> {
+    // only our own account gets to tell us what our bookmarks are
+    if message
+        .from
+        .as_ref()
+        .map_or(false, |from| bare(from) != ash.account().jid)
+    {
+        return vec![];
+    }
+    let items = message
+        .payloads
+        .iter()
+        .filter(|payload| payload.is("event", ns::PUBSUB_EVENT))
+        .filter_map(|event| event.get_child("items", ns::PUBSUB_EVENT))
+        .filter(|items| items.attr("node") == Some(NS_BOOKMARKS));
+    let mut stanzas = Vec::new();
+    for items in items {
+        for child in items.children() {
+            let id = match child.attr("id") {
+                Some(id) => id,
+                None => continue,
+            };
+            let conference = if child.is("item", ns::PUBSUB_EVENT) {
+                child.get_child("conference", NS_BOOKMARKS)
+            } else if child.is("retract", ns::PUBSUB_EVENT) {
+                None
+            } else {
+                continue;
+            };
+            stanzas.extend(apply(ash, id, conference));
+        }
+    }
+    stanzas
+}
+
+// brings a single bookmark into effect, conference is None when it's been removed
+fn apply(ash: &mut Ash, room: &str, conference: Option<&Element>) -> Option<Element> {
+    let jid: BareJid = room.parse().ok()?;
+    let autojoin = conference
+        .and_then(|c| c.attr("autojoin"))
+        .map_or(false, |a| a == "true" || a == "1");
+    if !autojoin {
+        // rooms from ash.toml or joined some other way aren't ours to leave
+        let room = ash.room_mut(&jid)?;
+        if !room.bookmarked {
+            return None;
+        }
+        let occupant = room.jid.clone();
+        ash.remove_room(&jid);
+        println!("leaving {jid}, bookmark removed");
+        return Some(make_leave(occupant));
+    }
+    if ash.room_mut(&jid).is_some() {
+        return None;
+    }
+    let conference = conference?;
+    let cfg = RoomConfig {
+        room: jid.to_string(),
+        nick: conference
+            .get_child("nick", NS_BOOKMARKS)
+            .map(|n| n.text())
+            .or_else(|| ash.room_template.nick.clone()),
+        password: conference
+            .get_child("password", NS_BOOKMARKS)
+            .map(|p| p.text()),
+        ..ash.room_template.clone()
+    };
+    let occupant = match ash.add_room(&cfg, ash.current) {
+        Ok(occupant) => occupant,
+        Err(e) => {
+            println!("ignoring bookmark {jid}: {e}");
+            return None;
+        }
+    };
+    if let Some(room) = ash.room_mut(&jid) {
+        room.bookmarked = true;
+    }
+    println!("joining {jid} from bookmarks");
+    Some(make_join(occupant, cfg.password))
+}
@@ -0,0 +1,191 @@
+use xmpp_parsers::message::Message;
+
+const NS_OOB: &str = "jabber:x:oob";
+
+// what's taken out of messages before they're learned, none of it makes sense in a generated
+// sentence
+pub struct Clean {
+    // XEP-0393 > quoted lines, usually someone else's words being replied to
+    pub quotes: bool,
+    // XEP-0393 *strong*, _emphasis_, ~strikethrough~ and `monospace` markers, keeping what's in
+    // them, and ``` preformatted blocks, dropping what's in them
+    pub styling: bool,
+    // XEP-0066 out of band urls, which is how uploaded files are sent
+    pub attachments: bool,
+}
+
+// the urls of files attached to message
+pub fn attachments(message: &Message) -> Vec<String> {
+    message
+        .payloads
+        .iter()
+        .filter(|payload| payload.is("x", NS_OOB))
+        .filter_map(|x| x.get_child("url", NS_OOB))
+        .map(|url| url.text().trim().to_string())
+        .filter(|url| !url.is_empty())
+        .collect()
+}
+
+// body with everything clean says to take out taken out, empty if that's everything
+pub fn clean(body: &str, attachments: &[String], clean: &Clean) -> String {
+    let mut lines = Vec::new();
+    let mut preformatted = false;
+    for line in body.lines() {
+        if clean.styling && line.starts_with("```") {
+            preformatted = !preformatted;
+            continue;
+        }
+        if preformatted || (clean.quotes && line.starts_with('>')) {
+            continue;
+        }
+        let line = if clean.styling {
+            unstyle(line)
+        } else {
+            line.to_string()
+        };
+        let words: Vec<&str> = line
+            .split_whitespace()
+            // aesgcm:// links are always OMEMO encrypted uploads, even without an <x/> for them
+            .filter(|word| {
+                !clean.attachments
+                    || !(attachments.iter().any(|url| url == word) || word.starts_with("aesgcm://"))
+            })
+            .collect();
+        if !words.is_empty() {
+            lines.push(words.join(" "));
+        }
+    }
+    lines.join("\n")
+}
+
+// line without span styling markers, per XEP-0393 a span opens at the start of the line or after
+// whitespace or another opening marker, and closes at the next of the same marker that doesn't
+// follow whitespace, with at least one character between them, spans of the same marker don't
+// nest so one pass is enough
+fn unstyle(line: &str) -> String {
+    const MARKERS: [char; 4] = ['*', '_', '~', '`'];
+    let chars: Vec<char> = line.chars().collect();
+    let mut markers = vec![false; chars.len()];
+    // where the span of each marker that's still open starts
+    let mut open = [None; MARKERS.len()];
+    for (i, c) in chars.iter().enumerate() {
+        let kind = match MARKERS.iter().position(|marker| marker == c) {
+            Some(kind) => kind,
+            None => continue,
+        };
+        match open[kind] {
+            Some(start) if i >= start + 2 && !chars[i - 1].is_whitespace() => {
+                markers[start] = true;
+                markers[i] = true;
+                open[kind] = None;
+            }
+            Some(_) => {}
+            None => {
+                // or straight after another span's opening marker, like *_this_*
+                if (i == 0 || chars[i - 1].is_whitespace() || open.contains(&Some(i - 1)))
+                    && chars.get(i + 1).map_or(false, |next| !next.is_whitespace())
+                {
+                    open[kind] = Some(i);
+                }
+            }
+        }
+    }
+    chars
+        .into_iter()
+        .zip(markers)
+        .filter(|(_, marker)| !marker)
+        .map(|(c, _)| c)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryFrom;
+    use xmpp_parsers::Element;
+
+    const ALL: Clean = Clean {
+        quotes: true,
+        styling: true,
+        attachments: true,
+    };
+
+    const NOTHING: Clean = Clean {
+        quotes: false,
+        styling: false,
+        attachments: false,
+    };
+
+    #[test]
+    fn quotes() {
+        assert_eq!(clean("> what they said\nmy answer", &[], &ALL), "my answer");
+        assert_eq!(clean("> only a quote", &[], &ALL), "");
+        assert_eq!(
+            clean("> what they said\nmy answer", &[], &NOTHING),
+            "> what they said\nmy answer"
+        );
+    }
+
+    #[test]
+    fn code_blocks() {
+        assert_eq!(
+            clean("look\n```\nfn main() {}\n```\nnice", &[], &ALL),
+            "look\nnice"
+        );
+        // never closed, so everything after it is code
+        assert_eq!(clean("look\n```\nfn main() {}", &[], &ALL), "look");
+    }
+
+    #[test]
+    fn spans() {
+        assert_eq!(unstyle("*bold* _it_ ~gone~ `code`"), "bold it gone code");
+        assert_eq!(unstyle("*_both_*"), "both");
+        assert_eq!(unstyle("a *long bold* bit"), "a long bold bit");
+        // no characters between, or whitespace on the wrong side
+        assert_eq!(unstyle("**"), "**");
+        assert_eq!(unstyle("* not bold *"), "* not bold *");
+        assert_eq!(unstyle("2 * 3 * 4"), "2 * 3 * 4");
+        assert_eq!(unstyle("snake_case_name"), "snake_case_name");
+    }
+
+    #[test]
+    fn unclosed_spans() {
+        assert_eq!(unstyle("*never closed"), "*never closed");
+        assert_eq!(unstyle("*a *b*"), "a *b");
+        let line = "*a ".repeat(100_000);
+        assert_eq!(unstyle(&line), line);
+    }
+
+    #[test]
+    fn attachment_urls() {
+        let message = Message::try_from(
+            "<message xmlns='jabber:client' from='room@muc.example.org/nick' type='groupchat'>\
+                <body>look https://upload.example.org/cat.png</body>\
+                <x xmlns='jabber:x:oob'><url>https://upload.example.org/cat.png</url></x>\
+            </message>"
+                .parse::<Element>()
+                .unwrap(),
+        )
+        .unwrap();
+        let urls = attachments(&message);
+        assert_eq!(urls, ["https://upload.example.org/cat.png"]);
+        assert_eq!(
+            clean("look https://upload.example.org/cat.png", &urls, &ALL),
+            "look"
+        );
+        assert_eq!(clean("https://upload.example.org/cat.png", &urls, &ALL), "");
+        // other links stay
+        assert_eq!(
+            clean("https://example.org/page", &urls, &ALL),
+            "https://example.org/page"
+        );
+        assert_eq!(
+            clean("aesgcm://upload.example.org/secret#key", &[], &ALL),
+            ""
+        );
+        assert_eq!(
+            clean("look https://upload.example.org/cat.png", &urls, &NOTHING),
+            "look https://upload.example.org/cat.png"
+        );
+    }
+}
@@ -0,0 +1,465 @@
+use crate::{Ash, Room, RoomConfig, Sender};
+use std::convert::TryFrom;
+use xmpp_parsers::{
+    data_forms::{DataForm, DataFormType},
+    disco::Item,
+    minidom::ElementBuilder,
+    ns,
+    stanza_error::{DefinedCondition, ErrorType, StanzaError},
+    BareJid, Element, Jid,
+};
+
+pub const NS_COMMANDS: &str = "http://jabber.org/protocol/commands";
+
+// node and human readable name of every XEP-0050 ad-hoc command we offer
+const COMMANDS: &[(&str, &str)] = &[
+    ("join", "Join a room"),
+    ("leave", "Leave a room"),
+    ("chattiness", "Set how chatty ash is in a room"),
+    ("mute", "Mute or unmute a room"),
+    ("maintenance", "Turn maintenance mode on or off"),
+    ("stats", "Show stats"),
+    ("rebuild", "Rebuild chains from the database"),
+];
+
+// commands, mostly privileged, that can be typed in chat, either addressed to ash in a room or messaged to
+// it directly
+pub enum TextCommand {
+    // join room@server [chains...]
+    Join(RoomConfig),
+    // leave [room@server], the room it's typed in by default
+    Leave(Option<BareJid>),
+    // mute|unmute [room@server], the room it's typed in by default
+    Mute(Option<BareJid>, bool),
+    // maintenance on|off, stops ash saying anything anywhere
+    Maintenance(bool),
+    // unlearn some text, forgets every message in the room it's typed in containing it
+    Unlearn(String),
+    // forget me, forgets everything whoever says it ever said, anyone can use this one
+    ForgetMe,
+}
+
+// recognizes a text command in body, which has had any address to ash removed, None means body
+// is just chat
+pub fn parse_text_command(body: &str) -> Option<TextCommand> {
+    let body = body.trim();
+    let (command, rest) = body.split_once(char::is_whitespace).unwrap_or((body, ""));
+    let rest = rest.trim();
+    // so "ash: join us" and "ash: leave me alone" aren't taken as commands
+    let room = |room: &str| room.parse::<BareJid>().ok().filter(|r| r.node.is_some());
+    let optional_room = |rest: &str| match rest {
+        "" => Some(None),
+        rest => room(rest).map(Some),
+    };
+    match command.to_lowercase().as_str() {
+        "join" => {
+            let mut words = rest.split_whitespace();
+            let room = room(words.next()?)?;
+            Some(TextCommand::Join(RoomConfig {
+                room: room.to_string(),
+                chain_indices: words
+                    .map(|c| c.parse())
+                    .collect::<Result<Vec<usize>, _>>()
+                    .ok()
+                    .filter(|c| !c.is_empty()),
+                ..Default::default()
+            }))
+        }
+        "leave" => Some(TextCommand::Leave(optional_room(rest)?)),
+        "mute" => Some(TextCommand::Mute(optional_room(rest)?, true)),
+        "unmute" => Some(TextCommand::Mute(optional_room(rest)?, false)),
+        "maintenance" if rest.eq_ignore_ascii_case("on") => Some(TextCommand::Maintenance(true)),
+        "maintenance" if rest.eq_ignore_ascii_case("off") => Some(TextCommand::Maintenance(false)),
+        "unlearn" if !rest.is_empty() => Some(TextCommand::Unlearn(rest.to_string())),
+        "forget" if rest.eq_ignore_ascii_case("me") => Some(TextCommand::ForgetMe),
+        _ => None,
+    }
+}
+
+// runs command typed by sender, in sender.room or directly to us, returns what to reply with,
+// stanzas that need to be sent as a result are pushed onto stanzas
+pub fn run_text_command(
+    ash: &mut Ash,
+    command: TextCommand,
+    sender: &Sender,
+    stanzas: &mut Vec<Element>,
+) -> String {
+    if let TextCommand::ForgetMe = command {
+        return match ash.forget(sender) {
+            Ok(0) if sender.identities().is_empty() => {
+                "I can't tell who you are, so I can't tell what you said".to_string()
+            }
+            Ok(forgotten) => format!("forgot {forgotten} of your messages"),
+            Err(e) => format!("can't forget you: {e}"),
+        };
+    }
+    // the room the command acts on, None for commands that aren't about any one room
+    let target = match &command {
+        TextCommand::Join(_) | TextCommand::Maintenance(_) | TextCommand::ForgetMe => None,
+        TextCommand::Leave(jid) | TextCommand::Mute(jid, _) => {
+            match jid.as_ref().or(sender.room.as_ref()) {
+                Some(jid) => Some(jid.clone()),
+                None => return "which room?".to_string(),
+            }
+        }
+        TextCommand::Unlearn(_) => match &sender.room {
+            Some(jid) => Some(jid.clone()),
+            None => return "say that in the room you want me to forget it in".to_string(),
+        },
+    };
+    if !ash.is_privileged(sender, target.as_ref()) {
+        return "only admins can do that".to_string();
+    }
+    match (command, target) {
+        (TextCommand::Join(cfg), _) => {
+            let jid = match cfg.room.parse::<BareJid>() {
+                Ok(jid) => jid,
+                Err(e) => return format!("can't join {}: {e}", cfg.room),
+            };
+            if ash.room_mut(&jid).is_some() {
+                return format!("already in {jid}");
+            }
+            match ash.join_room(&cfg) {
+                Ok(join) => {
+                    stanzas.push(join);
+                    format!("joining {jid}")
+                }
+                Err(e) => format!("can't join {jid}: {e}"),
+            }
+        }
+        (TextCommand::Leave(_), Some(jid)) => match ash.leave_room(&jid) {
+            Ok(true) => format!("leaving {jid}, bye!"),
+            Ok(false) => format!("not in {jid}"),
+            Err(e) => format!("can't leave {jid}: {e}"),
+        },
+        (TextCommand::Mute(_, muted), Some(jid)) => match ash.room_mut(&jid) {
+            Some(room) => {
+                room.muted = muted;
+                ash.refresh_presence(&jid);
+                format!("{jid} is now {}", if muted { "muted" } else { "unmuted" })
+            }
+            None => format!("not in {jid}"),
+        },
+        (TextCommand::Maintenance(maintenance), _) => {
+            ash.maintenance = maintenance;
+            ash.refresh_presences();
+            maintenance_note(maintenance)
+        }
+        (TextCommand::Unlearn(text), Some(jid)) => match ash.unlearn(&jid, &text) {
+            Ok(0) => "I never learned that".to_string(),
+            Ok(forgotten) => format!("forgot {forgotten} messages"),
+            Err(e) => format!("can't unlearn that: {e}"),
+        },
+        _ => unreachable!("room specific commands always have a target"),
+    }
+}
+
+// the commands listed to from in disco#items, only admins get to see any
+pub fn items(ash: &Ash, from: Option<&Jid>, to: Option<&Jid>) -> Vec<Item> {
+    match (from, to) {
+        (Some(from), Some(to)) if ash.is_admin(from) => COMMANDS
+            .iter()
+            .map(|(node, name)| Item {
+                jid: to.clone(),
+                node: Some(node.to_string()),
+                name: Some(name.to_string()),
+            })
+            .collect(),
+        _ => vec![],
+    }
+}
+
+// runs one step of an ad-hoc command, returning the <command/> to answer with, stanzas that
+// need to be sent as a result of the command are pushed onto stanzas
+pub fn execute(
+    ash: &mut Ash,
+    from: Option<&Jid>,
+    command: &Element,
+    stanzas: &mut Vec<Element>,
+) -> Result<Element, StanzaError> {
+    if !from.map_or(false, |from| ash.is_admin(from)) {
+        return Err(error(
+            ErrorType::Auth,
+            DefinedCondition::Forbidden,
+            "admins only",
+        ));
+    }
+    let node = command.attr("node").unwrap_or_default();
+    if !COMMANDS.iter().any(|(n, _)| *n == node) {
+        return Err(error(
+            ErrorType::Cancel,
+            DefinedCondition::ItemNotFound,
+            "no such command",
+        ));
+    }
+    let session = command
+        .attr("sessionid")
+        .map(|s| s.to_string())
+        .unwrap_or_else(new_session);
+    if command.attr("action") == Some("cancel") {
+        return Ok(response(node, &session, "canceled").build());
+    }
+    let form = command
+        .get_child("x", ns::DATA_FORMS)
+        .and_then(|x| DataForm::try_from(x.clone()).ok())
+        .filter(|form| form.type_ == DataFormType::Submit);
+
+    let note = match (node, form) {
+        ("stats", _) => {
+            let msgs: i64 = ash
+                .conn
+                .query_row("SELECT COUNT(*) FROM msg", [], |row| row.get(0))
+                .map_err(internal)?;
+            let mut stats = format!(
+                "I know {} words from {} messages, and am in {} rooms",
+                ash.brain[0].stats().get_total_words(),
+                msgs,
+                ash.rooms.len()
+            );
+            if ash.accounts.iter().any(|account| account.sm.wanted) {
+                let unacked: usize = ash
+                    .accounts
+                    .iter()
+                    .map(|account| account.sm.unacked())
+                    .sum();
+                stats.push_str(&format!(
+                    ", {unacked} messages are waiting to be acknowledged"
+                ));
+            }
+            stats
+        }
+        ("rebuild", _) => {
+            ash.rebuild_chains().map_err(internal)?;
+            format!("rebuilt {} chains", ash.brain.len())
+        }
+        (node, None) => {
+            return Ok(response(node, &session, "executing")
+                .append(
+                    Element::builder("actions", NS_COMMANDS)
+                        .attr("execute", "complete")
+                        .append(Element::builder("complete", NS_COMMANDS).build())
+                        .build(),
+                )
+                .append(form_for(ash, node))
+                .build());
+        }
+        ("join", Some(form)) => {
+            let jid = room_jid(&form)?;
+            // joining again would replace everything we know about the room
+            if ash.room_mut(&jid).is_some() {
+                return Err(bad_request("already in that room"));
+            }
+            let cfg = RoomConfig {
+                room: jid.to_string(),
+                nick: value(&form, "nick").map(|s| s.to_string()),
+                chain_indices: value(&form, "chain_indices")
+                    .map(|indices| {
+                        indices
+                            .split(|c: char| c == ',' || c.is_whitespace())
+                            .filter(|s| !s.is_empty())
+                            .map(|s| s.parse())
+                            .collect::<Result<Vec<usize>, _>>()
+                    })
+                    .transpose()
+                    .map_err(|_| bad_request("chain indices must be numbers"))?,
+                ..Default::default()
+            };
+            stanzas.push(
+                ash.join_room(&cfg)
+                    .map_err(|e| bad_request(&e.to_string()))?,
+            );
+            format!("joining {}", cfg.room)
+        }
+        ("leave", Some(form)) => {
+            let jid = room_jid(&form)?;
+            if !ash.leave_room(&jid).map_err(internal)? {
+                return Err(bad_request("not in that room"));
+            }
+            format!("left {jid}")
+        }
+        ("chattiness", Some(form)) => {
+            let jid = room_jid(&form)?;
+            let chattiness: f64 = required(&form, "chattiness")?
+                .parse()
+                .ok()
+                .filter(|c| (0.0..=1.0).contains(c))
+                .ok_or_else(|| bad_request("chattiness must be between 0 and 1"))?;
+            room_mut(ash, &jid)?.chattiness = chattiness;
+            format!("chattiness in {jid} is now {chattiness}")
+        }
+        ("mute", Some(form)) => {
+            let jid = room_jid(&form)?;
+            let muted = matches!(value(&form, "muted"), Some("1") | Some("true"));
+            room_mut(ash, &jid)?.muted = muted;
+            ash.refresh_presence(&jid);
+            format!("{jid} is now {}", if muted { "muted" } else { "unmuted" })
+        }
+        ("maintenance", Some(form)) => {
+            ash.maintenance = matches!(value(&form, "maintenance"), Some("1") | Some("true"));
+            ash.refresh_presences();
+            maintenance_note(ash.maintenance)
+        }
+        _ => unreachable!("checked against COMMANDS above"),
+    };
+    Ok(response(node, &session, "completed")
+        .append(
+            Element::builder("note", NS_COMMANDS)
+                .attr("type", "info")
+                .append(note)
+                .build(),
+        )
+        .build())
+}
+
+fn maintenance_note(maintenance: bool) -> String {
+    if maintenance {
+        "maintenance mode on, I'll keep quiet everywhere".to_string()
+    } else {
+        "maintenance mode off".to_string()
+    }
+}
+
+fn response(node: &str, session: &str, status: &str) -> ElementBuilder {
+    Element::builder("command", NS_COMMANDS)
+        .attr("node", node)
+        .attr("sessionid", session)
+        .attr("status", status)
+}
+
+// the form to fill in for commands that need one
+fn form_for(ash: &Ash, node: &str) -> Element {
+    let mut rooms: Vec<String> = ash
+        .rooms
+        .keys()
+        .filter(|(_, _, account)| *account == ash.current)
+        .map(|(node, domain, _)| format!("{node}@{domain}"))
+        .collect();
+    rooms.sort();
+    let (title, fields) = match node {
+        "join" => (
+            "Join a room",
+            vec![
+                field("room", "jid-single", "Room", true, &[]),
+                field("nick", "text-single", "Nick", false, &[]),
+                field(
+                    "chain_indices",
+                    "text-single",
+                    "Chain indices, comma separated",
+                    false,
+                    &[],
+                ),
+            ],
+        ),
+        "leave" => (
+            "Leave a room",
+            vec![field("room", "list-single", "Room", true, &rooms)],
+        ),
+        "chattiness" => (
+            "Set how chatty ash is in a room",
+            vec![
+                field("room", "list-single", "Room", true, &rooms),
+                field(
+                    "chattiness",
+                    "text-single",
+                    "Chance of speaking unprompted, 0 to 1",
+                    true,
+                    &[],
+                ),
+            ],
+        ),
+        "mute" => (
+            "Mute or unmute a room",
+            vec![
+                field("room", "list-single", "Room", true, &rooms),
+                field("muted", "boolean", "Muted", false, &[]),
+            ],
+        ),
+        "maintenance" => (
+            "Turn maintenance mode on or off",
+            vec![field(
+                "maintenance",
+                "boolean",
+                "Say nothing anywhere",
+                false,
+                &[],
+            )],
+        ),
+        _ => ("", vec![]),
+    };
+    Element::builder("x", ns::DATA_FORMS)
+        .attr("type", "form")
+        .append(
+            Element::builder("title", ns::DATA_FORMS)
+                .append(title)
+                .build(),
+        )
+        .append_all(fields)
+        .build()
+}
+
+fn field(var: &str, type_: &str, label: &str, required: bool, options: &[String]) -> Element {
+    let mut field = Element::builder("field", ns::DATA_FORMS)
+        .attr("var", var)
+        .attr("type", type_)
+        .attr("label", label);
+    if required {
+        field = field.append(Element::builder("required", ns::DATA_FORMS).build());
+    }
+    field
+        .append_all(options.iter().map(|option| {
+            Element::builder("option", ns::DATA_FORMS)
+                .append(
+                    Element::builder("value", ns::DATA_FORMS)
+                        .append(option.as_str())
+                        .build(),
+                )
+                .build()
+        }))
+        .build()
+}
+
+fn value<'a>(form: &'a DataForm, var: &str) -> Option<&'a str> {
+    form.fields
+        .iter()
+        .find(|field| field.var == var)
+        .and_then(|field| field.values.first())
+        .map(|value| value.trim())
+        .filter(|value| !value.is_empty())
+}
+
+fn required<'a>(form: &'a DataForm, var: &str) -> Result<&'a str, StanzaError> {
+    value(form, var).ok_or_else(|| bad_request(&format!("{var} is required")))
+}
+
+fn room_jid(form: &DataForm) -> Result<BareJid, StanzaError> {
+    required(form, "room")?
+        .parse()
+        .map_err(|_| bad_request("invalid room jid"))
+}
+
+fn room_mut<'a>(ash: &'a mut Ash, jid: &BareJid) -> Result<&'a mut Room, StanzaError> {
+    ash.room_mut(jid)
+        .ok_or_else(|| bad_request("not in that room"))
+}
+
+fn new_session() -> String {
+    use rand::Rng;
+    format!("{:016x}", rand::thread_rng().gen::<u64>())
+}
+
+fn bad_request(text: &str) -> StanzaError {
+    error(ErrorType::Modify, DefinedCondition::BadRequest, text)
+}
+
+fn internal(e: impl std::fmt::Display) -> StanzaError {
+    println!("command failed: {e}");
+    error(
+        ErrorType::Wait,
+        DefinedCondition::InternalServerError,
+        "something went wrong, check the logs",
+    )
+}
+
+fn error(type_: ErrorType, condition: DefinedCondition, text: &str) -> StanzaError {
+    StanzaError::new(type_, condition, "en", text)
+}
@@ -0,0 +1,259 @@
+use crate::{
+    login::{self, Login, Stream},
+    sm::{Resumption, Session},
+};
+use anyhow::{bail, Result};
+use futures::{sink::SinkExt, stream::StreamExt};
+use std::{future::Future, pin::Pin, sync::Arc, time::Duration};
+use tokio_xmpp::{AsyncClient as Client, Component, Packet};
+use xmpp_parsers::{minidom::Node, ns, Element, Jid};
+
+const NS_COMPONENT_ACCEPT: &str = "jabber:component:accept";
+
+// how long to wait before connecting again after losing a connection, or failing to make one
+const RECONNECT_DELAY: Duration = Duration::from_secs(10);
+
+// a connection being made, kept between calls to next so select! dropping it doesn't start over
+type Connecting<T> = Pin<Box<dyn Future<Output = Result<T>>>>;
+
+// how ash is connected, everything above this works the same either way
+pub enum XmppConnection {
+    Client(Client),
+    // a client we log in ourselves, see login.rs, reconnecting the same way components do
+    Login {
+        // None while connecting
+        stream: Option<Stream>,
+        connecting: Option<Connecting<(Stream, Session)>>,
+        login: Arc<Login>,
+        // the session to resume when logging in again, see resume_with
+        resume: Option<Resumption>,
+    },
+    // XEP-0114, whose stanzas are in a namespace of their own, and which tokio-xmpp doesn't
+    // reconnect by itself
+    Component {
+        // None while reconnecting
+        component: Option<Component>,
+        reconnecting: Option<Connecting<Component>>,
+        jid: Jid,
+        secret: String,
+        host: String,
+        port: u16,
+    },
+}
+
+pub enum Incoming {
+    // just connected, or reconnected, only ever to a resumed session when logging in ourselves
+    Online(Session),
+    // lost the connection, which is being made again
+    Offline,
+    Stanza(Element),
+}
+
+impl XmppConnection {
+    // connects once next is first called, the same as login
+    pub fn component(jid: Jid, secret: String, host: String, port: u16) -> Self {
+        let connecting = connect(jid.to_string(), secret.clone(), host.clone(), port);
+        XmppConnection::Component {
+            component: None,
+            reconnecting: Some(Box::pin(connecting)),
+            jid,
+            secret,
+            host,
+            port,
+        }
+    }
+
+    // connects once next is first called
+    pub fn login(settings: Login) -> Self {
+        let login = Arc::new(settings);
+        XmppConnection::Login {
+            stream: None,
+            connecting: Some(Box::pin(login::login(login.clone(), None))),
+            login,
+            resume: None,
+        }
+    }
+
+    // resume resumption's session the next time the connection is made, rather than start a new
+    // one, only done when logging in ourselves
+    pub fn resume_with(&mut self, resumption: Option<Resumption>) {
+        if let XmppConnection::Login { resume, .. } = self {
+            *resume = resumption;
+        }
+    }
+
+    // never ends, connections that are lost are made again
+    pub async fn next(&mut self) -> Incoming {
+        match self {
+            XmppConnection::Login {
+                stream,
+                connecting,
+                login,
+                resume,
+            } => loop {
+                // made here rather than on disconnecting, so it sees resume_with from in between
+                if stream.is_none() && connecting.is_none() {
+                    *connecting = Some(relogin(login, resume));
+                }
+                if let Some(pending) = connecting {
+                    match pending.await {
+                        Ok((connected, session)) => {
+                            *stream = Some(connected);
+                            *connecting = None;
+                            return Incoming::Online(session);
+                        }
+                        Err(e) => {
+                            println!("failed to log in as {}: {e}", login.jid);
+                            *connecting = Some(relogin(login, resume));
+                            continue;
+                        }
+                    }
+                }
+                let packet = match stream {
+                    Some(stream) => stream.next().await,
+                    None => None,
+                };
+                match packet {
+                    Some(Ok(Packet::Stanza(stanza))) => return Incoming::Stanza(stanza),
+                    Some(Ok(Packet::StreamEnd)) | Some(Err(_)) | None => {
+                        println!("{} disconnected, reconnecting", login.jid);
+                        *stream = None;
+                        return Incoming::Offline;
+                    }
+                    Some(Ok(_)) => {}
+                }
+            },
+            XmppConnection::Client(client) => match client.next().await {
+                Some(event) if event.is_online() => Incoming::Online(Session::New(false)),
+                Some(event) => event
+                    .into_stanza()
+                    .map_or(Incoming::Offline, Incoming::Stanza),
+                // only happens when reconnecting is turned off, which it never is
+                None => std::future::pending().await,
+            },
+            XmppConnection::Component {
+                component,
+                reconnecting,
+                jid,
+                secret,
+                host,
+                port,
+            } => loop {
+                if let Some(connecting) = reconnecting {
+                    match connecting.await {
+                        Ok(connected) => {
+                            *component = Some(connected);
+                            *reconnecting = None;
+                            return Incoming::Online(Session::New(false));
+                        }
+                        Err(e) => {
+                            println!("failed to connect {jid}: {e}");
+                            *reconnecting = Some(reconnect(jid, secret, host, *port));
+                            continue;
+                        }
+                    }
+                }
+                let stanza = match component {
+                    Some(component) => component.next().await,
+                    None => None,
+                };
+                match stanza {
+                    Some(stanza) => {
+                        return Incoming::Stanza(with_namespace(
+                            &stanza,
+                            NS_COMPONENT_ACCEPT,
+                            ns::DEFAULT_NS,
+                        ))
+                    }
+                    None => {
+                        println!("{jid} disconnected, reconnecting");
+                        *component = None;
+                        *reconnecting = Some(reconnect(jid, secret, host, *port));
+                        return Incoming::Offline;
+                    }
+                }
+            },
+        }
+    }
+
+    pub async fn send_stanza(&mut self, mut stanza: Element) -> Result<()> {
+        match self {
+            XmppConnection::Client(client) => client.send_stanza(stanza).await?,
+            XmppConnection::Login { stream, login, .. } => match stream {
+                Some(stream) => stream.send(Packet::Stanza(stanza)).await?,
+                None => bail!("{} is reconnecting", login.jid),
+            },
+            XmppConnection::Component { component, jid, .. } => {
+                let component = match component {
+                    Some(component) => component,
+                    None => bail!("{jid} is reconnecting"),
+                };
+                // the server fills this in for clients, but components can be anyone in their domain
+                if stanza.attr("from").is_none() {
+                    stanza.set_attr("from", jid.to_string());
+                }
+                component
+                    .send_stanza(with_namespace(&stanza, ns::DEFAULT_NS, NS_COMPONENT_ACCEPT))
+                    .await?
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn send_end(&mut self) -> Result<()> {
+        match self {
+            XmppConnection::Client(client) => client.send_end().await?,
+            XmppConnection::Login {
+                stream: Some(stream),
+                ..
+            } => stream.send(Packet::StreamEnd).await?,
+            XmppConnection::Login { .. } => {}
+            XmppConnection::Component {
+                component: Some(component),
+                ..
+            } => component.close().await?,
+            XmppConnection::Component { .. } => {}
+        }
+        Ok(())
+    }
+}
+
+// connects a component again, after waiting a while so a server that's down isn't hammered
+fn reconnect(jid: &Jid, secret: &str, host: &str, port: u16) -> Connecting<Component> {
+    let (jid, secret, host) = (jid.to_string(), secret.to_string(), host.to_string());
+    Box::pin(async move {
+        tokio::time::sleep(RECONNECT_DELAY).await;
+        connect(jid, secret, host, port).await
+    })
+}
+
+async fn connect(jid: String, secret: String, host: String, port: u16) -> Result<Component> {
+    Ok(Component::new(&jid, &secret, &host, port).await?)
+}
+
+// logs in again, after waiting a while the same as components do
+fn relogin(login: &Arc<Login>, resume: &Option<Resumption>) -> Connecting<(Stream, Session)> {
+    let (login, resume) = (login.clone(), resume.clone());
+    Box::pin(async move {
+        tokio::time::sleep(RECONNECT_DELAY).await;
+        login::login(login, resume).await
+    })
+}
+
+// element with everything in namespace from moved to namespace to, component stanzas are the same
+// as client ones otherwise, and xmpp-parsers only understands one of them
+fn with_namespace(element: &Element, from: &str, to: &str) -> Element {
+    let ns = element.ns();
+    let mut builder =
+        Element::builder(element.name(), if ns == from { to.to_string() } else { ns });
+    for (name, value) in element.attrs() {
+        builder = builder.attr(name.to_string(), value.to_string());
+    }
+    for node in element.nodes() {
+        builder = builder.append(match node {
+            Node::Element(child) => Node::Element(with_namespace(child, from, to)),
+            node => node.clone(),
+        });
+    }
+    builder.build()
+}
@@ -0,0 +1,75 @@
+use crate::bare;
+use xmpp_parsers::{
+    message::{Body, Message, MessageType},
+    ns, BareJid, Element, Jid,
+};
+
+const NS_CONFERENCE: &str = "jabber:x:conference";
+
+const DECLINE_REASON: &str = "Sorry, I only join rooms when invited by someone I trust.";
+
+pub struct Invite {
+    pub room: BareJid,
+    pub inviter: Jid,
+    pub password: Option<String>,
+    // mediated invites come through the room, direct ones straight from the inviter
+    pub mediated: bool,
+}
+
+// finds a XEP-0249 direct or XEP-0045 mediated invitation in message
+pub fn parse_invite(message: &Message) -> Option<Invite> {
+    let from = message.from.as_ref()?;
+    for payload in &message.payloads {
+        if payload.is("x", NS_CONFERENCE) {
+            return Some(Invite {
+                room: payload.attr("jid")?.parse().ok()?,
+                inviter: from.clone(),
+                password: payload.attr("password").map(|p| p.to_string()),
+                mediated: false,
+            });
+        }
+        if payload.is("x", ns::MUC_USER) {
+            if let Some(invite) = payload.get_child("invite", ns::MUC_USER) {
+                return Some(Invite {
+                    room: bare(from),
+                    inviter: invite.attr("from")?.parse().ok()?,
+                    password: payload
+                        .get_child("password", ns::MUC_USER)
+                        .map(|p| p.text()),
+                    mediated: true,
+                });
+            }
+        }
+    }
+    None
+}
+
+// Construct a refusal of invite, through the room if that's how it came or a plain chat
+// <message/> otherwise, since direct invitations have no way to decline
+pub fn make_decline(invite: &Invite) -> Element {
+    if invite.mediated {
+        let mut message = Message::new(Some(Jid::Bare(invite.room.clone())));
+        message.payloads.push(
+            Element::builder("x", ns::MUC_USER)
+                .append(
+                    Element::builder("decline", ns::MUC_USER)
+                        .attr("to", invite.inviter.to_string())
+                        .append(
+                            Element::builder("reason", ns::MUC_USER)
+                                .append(DECLINE_REASON)
+                                .build(),
+                        )
+                        .build(),
+                )
+                .build(),
+        );
+        message.into()
+    } else {
+        let mut message = Message::new(Some(invite.inviter.clone()));
+        message.type_ = MessageType::Chat;
+        message
+            .bodies
+            .insert(String::new(), Body(DECLINE_REASON.to_string()));
+        message.into()
+    }
+}
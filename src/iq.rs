@@ -0,0 +1,191 @@
+use crate::{
+    account::Account,
+    bookmarks::{self, NS_BOOKMARKS},
+    commands::{self, NS_COMMANDS},
+    owner, profile, Ash, NS_REFERENCE, NS_REPLY,
+};
+use std::convert::TryFrom;
+use xmpp_parsers::{
+    caps::{self, Caps},
+    disco::{
+        DiscoInfoQuery, DiscoInfoResult, DiscoItemsQuery, DiscoItemsResult, Feature, Identity,
+    },
+    hashes::Algo,
+    iq::{Iq, IqType},
+    ns,
+    ping::Ping,
+    stanza_error::{DefinedCondition, ErrorType, StanzaError},
+    version::{VersionQuery, VersionResult},
+    Element, Jid,
+};
+
+// identifies ash in XEP-0115 entity caps
+const CAPS_NODE: &str = "https://github.com/moparisthebest/ash";
+
+// everything we can answer or send, advertised in disco#info
+const FEATURES: &[&str] = &[
+    ns::DISCO_INFO,
+    ns::DISCO_ITEMS,
+    ns::VERSION,
+    ns::PING,
+    ns::MUC,
+    ns::CHATSTATES,
+    NS_REPLY,
+    NS_REFERENCE,
+    NS_COMMANDS,
+];
+
+// answers an <iq/> addressed to us, returns the response along with anything else that needs to
+// be sent as a result, or nothing if no response is due
+pub fn handle_iq(stanza: Element, ash: &mut Ash) -> Vec<Element> {
+    let iq = match Iq::try_from(stanza) {
+        Ok(iq) => iq,
+        Err(e) => {
+            println!("ignoring invalid iq: {e}");
+            return vec![];
+        }
+    };
+    let mut stanzas = Vec::new();
+    let result = match iq.payload {
+        IqType::Get(payload) => get(payload, ash, iq.from.as_ref(), iq.to.as_ref()),
+        IqType::Set(payload) if payload.is("command", NS_COMMANDS) => {
+            commands::execute(ash, iq.from.as_ref(), &payload, &mut stanzas).map(Some)
+        }
+        IqType::Set(_) => Err(service_unavailable()),
+        IqType::Result(Some(payload)) if bookmarks::is_result(ash, &iq.id, iq.from.as_ref()) => {
+            return bookmarks::handle_result(ash, &payload);
+        }
+        IqType::Result(payload) if profile::is_response(ash, &iq.id, iq.from.as_ref()) => {
+            return ash.accounts[ash.current]
+                .profile
+                .as_mut()
+                .map_or(vec![], |profile| {
+                    profile.handle_response(&iq.id, payload.as_ref())
+                });
+        }
+        // usually item-not-found, when nothing has been published yet
+        IqType::Error(_) if profile::is_response(ash, &iq.id, iq.from.as_ref()) => {
+            return ash.accounts[ash.current]
+                .profile
+                .as_mut()
+                .map_or(vec![], |profile| profile.handle_response(&iq.id, None));
+        }
+        IqType::Error(error) if iq.id == owner::CONFIG_ID => {
+            println!(
+                "failed to configure {}: {:?}",
+                iq.from.map_or_else(String::new, |from| from.to_string()),
+                error.defined_condition
+            );
+            return vec![];
+        }
+        IqType::Error(error) if iq.id == profile::PUBLISH_ID => {
+            println!("failed to publish profile: {:?}", error.defined_condition);
+            return vec![];
+        }
+        // never answer responses, or two entities could bounce errors between each other forever
+        IqType::Result(_) | IqType::Error(_) => return vec![],
+    };
+    let response = Iq {
+        from: None,
+        to: iq.from,
+        id: iq.id,
+        payload: match result {
+            Ok(result) => IqType::Result(result),
+            Err(error) => IqType::Error(error),
+        },
+    };
+    stanzas.insert(0, response.into());
+    stanzas
+}
+
+// a ping is answered with an empty result, everything else with a payload
+fn get(
+    payload: Element,
+    ash: &Ash,
+    from: Option<&Jid>,
+    to: Option<&Jid>,
+) -> Result<Option<Element>, StanzaError> {
+    if let Ok(query) = DiscoInfoQuery::try_from(payload.clone()) {
+        // entity caps queries come with our caps node#ver
+        if let Some(node) = query.node.as_deref() {
+            if !node.starts_with(&format!("{CAPS_NODE}#")) {
+                return Err(item_not_found());
+            }
+        }
+        let mut info = disco_info(ash.account());
+        info.node = query.node;
+        return Ok(Some(info.into()));
+    }
+    if let Ok(query) = DiscoItemsQuery::try_from(payload.clone()) {
+        let items = match query.node.as_deref() {
+            None => vec![],
+            Some(NS_COMMANDS) => commands::items(ash, from, to),
+            Some(_) => return Err(item_not_found()),
+        };
+        return Ok(Some(
+            DiscoItemsResult {
+                node: query.node,
+                items,
+            }
+            .into(),
+        ));
+    }
+    if VersionQuery::try_from(payload.clone()).is_ok() {
+        return Ok(Some(
+            VersionResult {
+                name: "ash".to_string(),
+                version: env!("CARGO_PKG_VERSION").to_string(),
+                os: None,
+            }
+            .into(),
+        ));
+    }
+    if Ping::try_from(payload).is_ok() {
+        return Ok(None);
+    }
+    Err(service_unavailable())
+}
+
+fn disco_info(account: &Account) -> DiscoInfoResult {
+    let mut features: Vec<Feature> = FEATURES.iter().map(|var| Feature::new(*var)).collect();
+    if account.bookmarks {
+        features.push(Feature::new(format!("{NS_BOOKMARKS}+notify")));
+    }
+    // a component is a service of the server rather than one of its clients
+    let identity = if account.component {
+        Identity::new("component", "generic", "en", "ash")
+    } else {
+        Identity::new("client", "bot", "en", "ash")
+    };
+    DiscoInfoResult {
+        node: None,
+        identities: vec![identity],
+        features,
+        extensions: vec![],
+    }
+}
+
+// account's XEP-0115 entity caps, to be included in presence
+pub fn make_caps(account: &Account) -> Element {
+    let disco = caps::compute_disco(&disco_info(account));
+    let hash = caps::hash_caps(&disco, Algo::Sha_1).expect("sha-1 is always supported");
+    Caps::new(CAPS_NODE, hash).into()
+}
+
+fn service_unavailable() -> StanzaError {
+    StanzaError::new(
+        ErrorType::Cancel,
+        DefinedCondition::ServiceUnavailable,
+        "en",
+        "ash doesn't support this",
+    )
+}
+
+fn item_not_found() -> StanzaError {
+    StanzaError::new(
+        ErrorType::Cancel,
+        DefinedCondition::ItemNotFound,
+        "en",
+        "no such node",
+    )
+}
@@ -0,0 +1,502 @@
+use crate::{
+    sm::{Resumption, Session, NS_SM},
+    ConnectionConfig,
+};
+use anyhow::{anyhow, bail, Result};
+use futures::{sink::SinkExt, stream::StreamExt};
+use rustls::{
+    client::{ServerCertVerified, ServerCertVerifier},
+    Certificate, ClientConfig, OwnedTrustAnchor, RootCertStore, ServerName,
+};
+use sasl::{
+    client::{
+        mechanisms::{Plain, Scram},
+        Mechanism,
+    },
+    common::{
+        scram::{Sha1, Sha256},
+        ChannelBinding, Credentials,
+    },
+};
+use sha2::Digest;
+use std::{convert::TryFrom, fs, str::FromStr, sync::Arc, time::SystemTime};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::TcpStream,
+};
+use tokio_rustls::{client::TlsStream, TlsConnector};
+use tokio_xmpp::{xmpp_stream::XMPPStream, Packet};
+use xmpp_parsers::{
+    bind::BindQuery,
+    iq::{Iq, IqType},
+    ns,
+    sasl::{Auth, Challenge, Failure, Mechanism as SaslMechanism, Response, Success},
+    BareJid, Element, Jid,
+};
+
+const NS_TLS: &str = "urn:ietf:params:xml:ns:xmpp-tls";
+const NS_SASL: &str = "urn:ietf:params:xml:ns:xmpp-sasl";
+const NS_BIND: &str = "urn:ietf:params:xml:ns:xmpp-bind";
+
+// a stream to the server, encrypted whichever way it was connected
+pub type Stream = XMPPStream<TlsStream<TcpStream>>;
+
+// how to log in as a client account ourselves, for what tokio-xmpp's client can't do: XEP-0368
+// direct TLS, trusting a CA or certificate of our own, and connecting through a SOCKS5 proxy
+pub struct Login {
+    pub jid: BareJid,
+    resource: Option<String>,
+    password: String,
+    host: String,
+    port: u16,
+    direct_tls: bool,
+    tls: Arc<ClientConfig>,
+    // host:port of a SOCKS5 proxy to connect through
+    proxy: Option<String>,
+}
+
+impl Login {
+    pub fn new(
+        jid: &BareJid,
+        resource: Option<&String>,
+        password: &str,
+        cfg: Option<&ConnectionConfig>,
+    ) -> Result<Self> {
+        let direct_tls = cfg.and_then(|cfg| cfg.direct_tls).unwrap_or(false);
+        Ok(Self {
+            jid: jid.clone(),
+            resource: resource.cloned(),
+            password: password.to_string(),
+            // no SRV lookup here, set host if the server isn't at the jid's domain
+            host: cfg
+                .and_then(|cfg| cfg.host.clone())
+                .unwrap_or_else(|| jid.domain.clone()),
+            port: cfg
+                .and_then(|cfg| cfg.port)
+                .unwrap_or(if direct_tls { 5223 } else { 5222 }),
+            direct_tls,
+            tls: tls_config(cfg, direct_tls)?,
+            proxy: cfg
+                .and_then(|cfg| cfg.proxy.as_deref())
+                .map(parse_proxy)
+                .transpose()?,
+        })
+    }
+
+    // whether cfg needs logging in ourselves rather than through tokio-xmpp
+    pub fn needed(cfg: Option<&ConnectionConfig>) -> bool {
+        cfg.map_or(false, |cfg| {
+            cfg.direct_tls.unwrap_or(false)
+                || cfg.ca_file.is_some()
+                || cfg.pin_sha256.is_some()
+                || cfg.proxy.is_some()
+        })
+    }
+}
+
+// connects, authenticates and resumes the session in resume, or binds a resource for a new one,
+// ready for stanzas
+pub async fn login(login: Arc<Login>, resume: Option<Resumption>) -> Result<(Stream, Session)> {
+    let stream = connect(&login).await?;
+    let username = match &login.jid.node {
+        Some(node) => node.clone(),
+        None => bail!("{} has no username to log in with", login.jid),
+    };
+    let credentials = Credentials::default()
+        .with_username(username)
+        .with_password(login.password.clone())
+        .with_channel_binding(ChannelBinding::None);
+    let stream = authenticate(stream, credentials).await?;
+    let mut stream = XMPPStream::start(
+        stream,
+        Jid::Bare(login.jid.clone()),
+        ns::DEFAULT_NS.to_string(),
+    )
+    .await?;
+    let sm = stream.stream_features.has_child("sm", NS_SM);
+    if let Some(resume) = resume.filter(|_| sm) {
+        if let Some(h) = resumed(&mut stream, resume).await? {
+            return Ok((stream, Session::Resumed(h)));
+        }
+    }
+    if !stream.stream_features.has_child("bind", NS_BIND) {
+        bail!("{} doesn't offer resource binding", login.host);
+    }
+    match query(
+        &mut stream,
+        "ash-bind",
+        IqType::Set(BindQuery::new(login.resource.clone()).into()),
+    )
+    .await?
+    {
+        IqType::Result(_) => Ok((stream, Session::New(sm))),
+        IqType::Error(error) => bail!("failed to bind a resource: {:?}", error.defined_condition),
+        _ => bail!("failed to bind a resource"),
+    }
+}
+
+// asks the server to carry on with the session in resume, returns how many of the stanzas we sent
+// on it the server got, None if it can't be resumed and a new one is needed
+async fn resumed(stream: &mut Stream, resume: Resumption) -> Result<Option<u32>> {
+    let request = Element::builder("resume", NS_SM)
+        .attr("previd", resume.id)
+        .attr("h", resume.h.to_string())
+        .build();
+    stream.send(Packet::Stanza(request)).await?;
+    while let Some(packet) = stream.next().await {
+        let element = match packet? {
+            Packet::Stanza(element) if element.ns() == NS_SM => element,
+            _ => continue,
+        };
+        match element.name() {
+            "resumed" => {
+                return match element.attr("h").and_then(|h| h.parse().ok()) {
+                    Some(h) => Ok(Some(h)),
+                    None => bail!("server resumed the session without saying what it got"),
+                }
+            }
+            "failed" => {
+                println!("server can't resume the session, starting a new one");
+                return Ok(None);
+            }
+            _ => {}
+        }
+    }
+    bail!("disconnected before the server resumed the session")
+}
+
+// connects to the server, over TLS from the start or STARTTLS, which is always required
+pub async fn connect(login: &Login) -> Result<Stream> {
+    let jid = Jid::Bare(login.jid.clone());
+    let tcp = match &login.proxy {
+        Some(proxy) => socks5(proxy, &login.host, login.port).await?,
+        None => TcpStream::connect((login.host.as_str(), login.port)).await?,
+    };
+    let tcp = if login.direct_tls {
+        tcp
+    } else {
+        let mut stream = XMPPStream::start(tcp, jid.clone(), ns::DEFAULT_NS.to_string()).await?;
+        stream
+            .send(Packet::Stanza(Element::builder("starttls", NS_TLS).build()))
+            .await?;
+        loop {
+            match stream.next().await {
+                Some(Ok(Packet::Stanza(stanza))) if stanza.is("proceed", NS_TLS) => break,
+                Some(Ok(Packet::Text(_))) => {}
+                Some(Err(e)) => return Err(e.into()),
+                _ => bail!("{} refused STARTTLS, not connecting without it", login.host),
+            }
+        }
+        stream.into_inner()
+    };
+    let tls = TlsConnector::from(login.tls.clone())
+        .connect(ServerName::try_from(login.jid.domain.as_str())?, tcp)
+        .await?;
+    Ok(XMPPStream::start(tls, jid, ns::DEFAULT_NS.to_string()).await?)
+}
+
+// socks5h://host:port, or socks5://, names are always looked up by the proxy either way
+fn parse_proxy(proxy: &str) -> Result<String> {
+    let address = proxy
+        .strip_prefix("socks5h://")
+        .or_else(|| proxy.strip_prefix("socks5://"))
+        .map(|address| address.trim_end_matches('/'))
+        .unwrap_or_default();
+    match address.rsplit_once(':') {
+        Some((host, port)) if !host.is_empty() && port.parse::<u16>().is_ok() => {
+            Ok(address.to_string())
+        }
+        _ => bail!("proxy must look like socks5h://host:port, not {proxy}"),
+    }
+}
+
+// connects to host and port through the SOCKS5 proxy, which looks host up itself, so nothing
+// about where we're going goes through our own DNS, and onion services can be reached
+async fn socks5(proxy: &str, host: &str, port: u16) -> Result<TcpStream> {
+    let mut stream = TcpStream::connect(proxy).await?;
+    // version 5, offering only no authentication
+    stream.write_all(&[5, 1, 0]).await?;
+    let mut method = [0; 2];
+    stream.read_exact(&mut method).await?;
+    if method != [5, 0] {
+        bail!("SOCKS5 proxy {proxy} wants authentication");
+    }
+    let name = host.as_bytes();
+    if name.len() > 255 {
+        bail!("{host} is too long for SOCKS5");
+    }
+    // CONNECT to a domain name
+    let mut request = vec![5, 1, 0, 3, name.len() as u8];
+    request.extend_from_slice(name);
+    request.extend_from_slice(&port.to_be_bytes());
+    stream.write_all(&request).await?;
+    let mut reply = [0; 4];
+    stream.read_exact(&mut reply).await?;
+    if reply[0] != 5 {
+        bail!("{proxy} isn't a SOCKS5 proxy");
+    }
+    if reply[1] != 0 {
+        bail!(
+            "SOCKS5 proxy {proxy} couldn't connect to {host}:{port}, error {}",
+            reply[1]
+        );
+    }
+    // then the address the proxy connected from, which is no use to us
+    let length = match reply[3] {
+        1 => 4,
+        4 => 16,
+        3 => {
+            let mut length = [0; 1];
+            stream.read_exact(&mut length).await?;
+            length[0] as usize
+        }
+        kind => bail!("SOCKS5 proxy {proxy} sent an unknown address type {kind}"),
+    };
+    let mut bound = vec![0; length + 2];
+    stream.read_exact(&mut bound).await?;
+    Ok(stream)
+}
+
+// the webpki roots, or the CAs in ca_file instead, or only the certificate pinned by pin_sha256
+fn tls_config(cfg: Option<&ConnectionConfig>, direct_tls: bool) -> Result<Arc<ClientConfig>> {
+    let builder = ClientConfig::builder().with_safe_defaults();
+    let mut config = match (
+        cfg.and_then(|cfg| cfg.ca_file.as_ref()),
+        cfg.and_then(|cfg| cfg.pin_sha256.as_ref()),
+    ) {
+        (Some(_), Some(_)) => bail!("set only one of ca_file or pin_sha256"),
+        (None, Some(pin)) => builder
+            .with_custom_certificate_verifier(Arc::new(Pinned(parse_pin(pin)?)))
+            .with_no_client_auth(),
+        (Some(path), None) => {
+            let mut roots = RootCertStore::empty();
+            let pem = fs::read_to_string(path)?;
+            let certificates = pem_certificates(&pem)?;
+            if certificates.is_empty() {
+                bail!("no certificates in {path}");
+            }
+            for certificate in certificates {
+                roots
+                    .add(&Certificate(certificate))
+                    .map_err(|e| anyhow!("bad certificate in {path}: {e:?}"))?;
+            }
+            builder.with_root_certificates(roots).with_no_client_auth()
+        }
+        (None, None) => {
+            let mut roots = RootCertStore::empty();
+            roots.add_server_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.0.iter().map(|anchor| {
+                OwnedTrustAnchor::from_subject_spki_name_constraints(
+                    anchor.subject,
+                    anchor.spki,
+                    anchor.name_constraints,
+                )
+            }));
+            builder.with_root_certificates(roots).with_no_client_auth()
+        }
+    };
+    // XEP-0368 says to, so servers sharing port 443 with a web server can tell us apart
+    if direct_tls {
+        config.alpn_protocols = vec![b"xmpp-client".to_vec()];
+    }
+    Ok(Arc::new(config))
+}
+
+// the DER of every certificate in a PEM file
+fn pem_certificates(pem: &str) -> Result<Vec<Vec<u8>>> {
+    let mut certificates = Vec::new();
+    let mut current: Option<String> = None;
+    for line in pem.lines().map(str::trim) {
+        match line {
+            "-----BEGIN CERTIFICATE-----" => current = Some(String::new()),
+            "-----END CERTIFICATE-----" => {
+                if let Some(encoded) = current.take() {
+                    certificates.push(base64::decode(encoded)?);
+                }
+            }
+            line => {
+                if let Some(encoded) = &mut current {
+                    encoded.push_str(line);
+                }
+            }
+        }
+    }
+    Ok(certificates)
+}
+
+// hex, with or without the colons openssl puts between bytes
+fn parse_pin(pin: &str) -> Result<Vec<u8>> {
+    let hex: String = pin.chars().filter(|c| *c != ':').collect();
+    if hex.len() != 64 || !hex.is_ascii() {
+        bail!("pin_sha256 must be the 64 hex digits of a SHA-256 fingerprint");
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| Ok(u8::from_str_radix(&hex[i..i + 2], 16)?))
+        .collect()
+}
+
+// trusts exactly one certificate, by its SHA-256, whoever signed it and whatever names it's for,
+// which is how a self-signed certificate can be trusted
+struct Pinned(Vec<u8>);
+
+impl ServerCertVerifier for Pinned {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if sha2::Sha256::digest(&end_entity.0).as_slice() == self.0 {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::General(
+                "certificate doesn't match pin_sha256".to_string(),
+            ))
+        }
+    }
+}
+
+// SASL with the best mechanism the server offers, returns the stream to be started again
+async fn authenticate(
+    mut stream: Stream,
+    credentials: Credentials,
+) -> Result<TlsStream<TcpStream>> {
+    let offered: Vec<String> = stream
+        .stream_features
+        .get_child("mechanisms", NS_SASL)
+        .into_iter()
+        .flat_map(|mechanisms| mechanisms.children())
+        .filter(|mechanism| mechanism.is("mechanism", NS_SASL))
+        .map(|mechanism| mechanism.text())
+        .collect();
+    let mut mechanism: Box<dyn Mechanism> = if offered.iter().any(|m| m == "SCRAM-SHA-256") {
+        Box::new(Scram::<Sha256>::from_credentials(credentials).map_err(|e| anyhow!("{e:?}"))?)
+    } else if offered.iter().any(|m| m == "SCRAM-SHA-1") {
+        Box::new(Scram::<Sha1>::from_credentials(credentials).map_err(|e| anyhow!("{e:?}"))?)
+    } else if offered.iter().any(|m| m == "PLAIN") {
+        // only ever over TLS, which connect makes sure of
+        Box::new(Plain::from_credentials(credentials).map_err(|e| anyhow!("{e:?}"))?)
+    } else {
+        bail!("no SASL mechanism in common with the server, it offers {offered:?}");
+    };
+    let auth = Auth {
+        mechanism: SaslMechanism::from_str(mechanism.name())?,
+        data: mechanism.initial(),
+    };
+    stream.send(Packet::Stanza(auth.into())).await?;
+    loop {
+        let stanza = match stream.next().await {
+            Some(Ok(Packet::Stanza(stanza))) => stanza,
+            Some(Ok(_)) => continue,
+            Some(Err(e)) => return Err(e.into()),
+            None => bail!("disconnected while logging in"),
+        };
+        if let Ok(challenge) = Challenge::try_from(stanza.clone()) {
+            let data = mechanism
+                .response(&challenge.data)
+                .map_err(|e| anyhow!("{e:?}"))?;
+            stream
+                .send(Packet::Stanza(Response { data }.into()))
+                .await?;
+        } else if Success::try_from(stanza.clone()).is_ok() {
+            return Ok(stream.into_inner());
+        } else if let Ok(failure) = Failure::try_from(stanza) {
+            bail!("failed to log in: {:?}", failure.defined_condition);
+        }
+    }
+}
+
+// sends an <iq/> and waits for the answer to it, ignoring anything else the server sends
+pub async fn query<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut XMPPStream<S>,
+    id: &str,
+    payload: IqType,
+) -> Result<IqType> {
+    let iq = Iq {
+        from: None,
+        to: None,
+        id: id.to_string(),
+        payload,
+    };
+    stream.send(Packet::Stanza(iq.into())).await?;
+    while let Some(packet) = stream.next().await {
+        if let Packet::Stanza(stanza) = packet? {
+            if stanza.is("iq", ns::DEFAULT_NS) && stanza.attr("id") == Some(id) {
+                return Ok(Iq::try_from(stanza)?.payload);
+            }
+        }
+    }
+    bail!("disconnected before the server answered")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    // a stand-in SOCKS5 proxy, which checks it's asked for example.onion:5222 by name, answers
+    // the CONNECT with reply, and then says hello if it succeeded
+    async fn proxy(reply: u8) -> (String, tokio::task::JoinHandle<()>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut greeting = [0; 3];
+            stream.read_exact(&mut greeting).await.unwrap();
+            assert_eq!(greeting, [5, 1, 0]);
+            stream.write_all(&[5, 0]).await.unwrap();
+            let mut request = [0; 5];
+            stream.read_exact(&mut request).await.unwrap();
+            assert_eq!(request[..4], [5, 1, 0, 3]);
+            let mut destination = vec![0; request[4] as usize + 2];
+            stream.read_exact(&mut destination).await.unwrap();
+            let (host, port) = destination.split_at(request[4] as usize);
+            assert_eq!(host, b"example.onion");
+            assert_eq!(port, 5222u16.to_be_bytes());
+            stream
+                .write_all(&[5, reply, 0, 1, 127, 0, 0, 1, 0x23, 0x28])
+                .await
+                .unwrap();
+            if reply == 0 {
+                stream.write_all(b"hello").await.unwrap();
+            }
+        });
+        (address, server)
+    }
+
+    #[tokio::test]
+    async fn socks5_leaves_dns_to_the_proxy() {
+        let (address, server) = proxy(0).await;
+        let mut stream = socks5(&address, "example.onion", 5222).await.unwrap();
+        let mut hello = [0; 5];
+        stream.read_exact(&mut hello).await.unwrap();
+        assert_eq!(&hello, b"hello");
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn socks5_refused() {
+        // connection refused
+        let (address, server) = proxy(5).await;
+        let error = socks5(&address, "example.onion", 5222).await.unwrap_err();
+        assert!(error.to_string().contains("error 5"));
+        server.await.unwrap();
+    }
+
+    #[test]
+    fn proxy_urls() {
+        assert_eq!(
+            parse_proxy("socks5h://127.0.0.1:9050").unwrap(),
+            "127.0.0.1:9050"
+        );
+        assert_eq!(
+            parse_proxy("socks5://proxy.example.org:1080/").unwrap(),
+            "proxy.example.org:1080"
+        );
+        assert!(parse_proxy("http://127.0.0.1:8080").is_err());
+        assert!(parse_proxy("socks5h://127.0.0.1").is_err());
+    }
+}
@@ -1,40 +1,171 @@
-use anyhow::Result;
+use account::Account;
+use anyhow::{bail, Result};
+use connection::Incoming;
 use die::{die, Die};
-use futures::stream::StreamExt;
-use rusqlite::Connection;
+use futures::future::select_all;
+use invites::Invite;
+use profile::Profile;
+use rusqlite::{params, Connection};
 use rustkov::prelude::Brain;
 use serde_derive::Deserialize;
+use sm::Session;
 use std::{
-    collections::HashMap,
+    collections::{HashMap, VecDeque},
     convert::TryFrom,
     env::args,
     fs::File,
     io::Read,
     iter::Iterator,
     ops::Sub,
-    path::Path,
-    time::{Duration, Instant},
+    path::{Path, PathBuf},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
-use tokio_xmpp::AsyncClient as Client;
+use tokio::sync::mpsc;
 use xmpp_parsers::{
-    message::{Body, Message, MessageType},
-    muc::{muc::History, Muc},
-    presence::{Presence, Type as PresenceType},
+    chatstates::ChatState,
+    message::{Body, Message, MessageType, Subject},
+    muc::{
+        muc::History,
+        user::{Affiliation, MucUser, Role, Status},
+        Muc,
+    },
+    ns,
+    presence::{Presence, Show, Type as PresenceType},
+    stanza_id::StanzaId,
     BareJid, Element, FullJid, Jid,
 };
 
+mod account;
+mod bookmarks;
+mod clean;
+mod commands;
+mod connection;
+mod invites;
+mod iq;
+mod login;
+mod owner;
+mod profile;
+mod register;
+mod sm;
+
+const NS_REPLY: &str = "urn:xmpp:reply:0";
+const NS_FALLBACK: &str = "urn:xmpp:fallback:0";
+const NS_REFERENCE: &str = "urn:xmpp:reference:0";
+const NS_OCCUPANT_ID: &str = "urn:xmpp:occupant-id:0";
+
+// nobody wants to wait longer than this for a reply, no matter how long it is
+const MAX_TYPING_SECONDS: f64 = 15.0;
+
+// netsplits, and bridges like biboumi reconnecting to IRC, bring everyone back at once, this many
+// joins within FLOOD_WINDOW means it's one of those rather than people to welcome
+const FLOOD_JOINS: usize = 5;
+const FLOOD_WINDOW: Duration = Duration::from_secs(10);
+// how long greetings stay off after a flood of joins
+const FLOOD_QUIET: Duration = Duration::from_secs(120);
+
 struct Room {
+    // which of ash.accounts is in it
+    account: usize,
     nick: String,
     chain_indices: Vec<usize>,
     jid: FullJid,
+    password: Option<String>,
+    aliases: Vec<String>,
+    threaded_replies: bool,
+    typing_wpm: u32,
+    typing_jitter: f64,
+    // chance of saying something unprompted
+    chattiness: f64,
+    // muted rooms are still learned from, but never spoken in
+    muted: bool,
+    // joined because of an autojoin bookmark, and left when it goes away
+    bookmarked: bool,
+    // privileged in this room only, on top of the global admins
+    admins: Vec<BareJid>,
+    // for semi-anonymous rooms where real jids can't be seen, only trustworthy if the room
+    // supports XEP-0421
+    admin_occupant_ids: Vec<String>,
+    // whether room owners and admins are privileged in it
+    affiliation_admins: bool,
+    // by nick, from MUC presence, including ourselves
+    occupants: HashMap<String, Occupant>,
+    // until our own presence comes back, the presences we get are whoever was already here
+    joined: bool,
+    // None if nobody joining is greeted
+    greet: Option<Greet>,
+    // as last set, None if there isn't one
+    subject: Option<String>,
+    // when a message was last said in here, to catch up on what was missed after reconnecting
+    last_heard: Option<Instant>,
+    // whether "topic" changes the subject rather than only suggesting one
+    set_topic: bool,
+    // how to configure the room if joining it creates it, instant with the server's defaults if None
+    create: Option<CreateConfig>,
+    // what's taken out of messages before they're learned
+    clean: clean::Clean,
+    // when occupants recently joined, to tell floods of joins apart
+    recent_joins: VecDeque<Instant>,
+    greet_quiet_until: Instant,
 
     last_sent_jabber: Instant,
     last_sent_dad: Instant,
     last_sent_random: Instant,
+    last_sent_greeting: Instant,
+}
+
+struct Greet {
+    // with {nick} replaced by whoever joined, or a line generated from their nick when None
+    template: Option<String>,
+    // seconds someone must have been gone to be greeted again, only first visits when None
+    absence: Option<u64>,
+    cooldown: u64,
+    chance: f64,
 }
 
 impl Room {
-    // executed for every "botname: command-here" message with the nick and whitespace trimmed from front
+    // if this message is addressed to us, by name anywhere in the body or by XEP-0372 mention,
+    // returns the body with the address removed
+    fn addressed(&self, body: &str, message: &Message) -> Option<String> {
+        if let Some(reference) = self.mention(message) {
+            let begin = reference.attr("begin").and_then(|b| b.parse().ok());
+            let end = reference.attr("end").and_then(|e| e.parse().ok());
+            return Some(match (begin, end) {
+                (Some(begin), Some(end)) => {
+                    strip_chars(body, begin, end).unwrap_or_else(|| body.to_string())
+                }
+                _ => body.to_string(),
+            });
+        }
+        std::iter::once(&self.nick)
+            .chain(self.aliases.iter())
+            .find_map(|name| strip_address(body, name))
+    }
+
+    // like addressed, but only when the address comes first, like "ash: mute", which is how text
+    // commands are given, so "don't mute ash" is just chat
+    fn commanded(&self, body: &str, message: &Message) -> Option<String> {
+        if let Some(reference) = self.mention(message) {
+            return match reference.attr("begin") {
+                Some("0") => self.addressed(body, message),
+                _ => None,
+            };
+        }
+        std::iter::once(&self.nick)
+            .chain(self.aliases.iter())
+            .find_map(|name| strip_leading_address(body, name))
+    }
+
+    // the XEP-0372 reference mentioning us in message, if there is one
+    fn mention<'a>(&self, message: &'a Message) -> Option<&'a Element> {
+        let uri = format!("xmpp:{}", self.jid);
+        message.payloads.iter().find(|p| {
+            p.is("reference", NS_REFERENCE)
+                && p.attr("type") == Some("mention")
+                && p.attr("uri") == Some(uri.as_str())
+        })
+    }
+
+    // executed for every message addressed to us, like "botname: command-here", with the address removed
     fn directed_message(&mut self, orig_body: &str, brain: &mut Brain) -> Result<Option<String>> {
         let body = orig_body.to_lowercase();
         Ok(match body.as_str() {
@@ -59,7 +190,7 @@ impl Room {
         if should_send(&body, &mut self.last_sent_dad, "dad", 300, 0.5) {
             return Ok(choose(DAD_JOKES));
         }
-        if should_send(&body, &mut self.last_sent_random, "", 300, 0.01) {
+        if should_send(&body, &mut self.last_sent_random, "", 300, self.chattiness) {
             // 50% chance dad joke vs brain
             return Ok(if chance(0.5) {
                 choose(DAD_JOKES)
@@ -70,33 +201,923 @@ impl Room {
         Ok(None)
     }
 
-    fn new(nick: String, jid: FullJid, chain_indices: Vec<usize>) -> Self {
+    // our own occupant, once we're in
+    fn us(&self) -> Option<&Occupant> {
+        self.occupants.get(&self.nick)
+    }
+
+    // visitors in moderated rooms aren't allowed to send messages
+    fn can_speak(&self) -> bool {
+        self.us().map_or(true, |us| us.role != Role::Visitor)
+    }
+
+    // nobody but us is in here, so there's nobody to talk to
+    fn is_empty(&self) -> bool {
+        self.occupants.keys().all(|nick| nick == &self.nick)
+    }
+
+    // who nick is, as far as this room lets us know
+    fn sender(&self, nick: &str) -> Sender {
+        let occupant = self.occupants.get(nick);
+        Sender {
+            jid: occupant.and_then(|o| o.jid.clone()),
+            room: Some(self.jid.clone().into()),
+            affiliation: occupant.map(|o| o.affiliation.clone()),
+            occupant_id: occupant.and_then(|o| o.occupant_id.clone()),
+        }
+    }
+
+    // a new subject for the room, made up from what's been said in it
+    fn topic(&self, brain: &mut Brain) -> Result<Option<String>> {
+        let seed = self.subject.as_ref().or(self.jid.node.as_ref());
+        brain.generate(seed.map_or("", |seed| seed.as_str()))
+    }
+
+    // only moderators can be sure the room lets them change the subject
+    fn can_set_subject(&self) -> bool {
+        self.set_topic && self.us().map_or(false, |us| us.role == Role::Moderator)
+    }
+
+    // what to say to nick, who just joined after being gone absent seconds, or for the first time
+    fn greeting(
+        &mut self,
+        nick: &str,
+        absent: Option<u64>,
+        brain: &mut Brain,
+    ) -> Result<Option<String>> {
+        let greet = match &self.greet {
+            Some(greet) => greet,
+            None => return Ok(None),
+        };
+        let now = Instant::now();
+        self.recent_joins.push_back(now);
+        while let Some(&joined) = self.recent_joins.front() {
+            if now - joined <= FLOOD_WINDOW {
+                break;
+            }
+            self.recent_joins.pop_front();
+        }
+        if self.recent_joins.len() >= FLOOD_JOINS {
+            self.greet_quiet_until = now + FLOOD_QUIET;
+        }
+        if now < self.greet_quiet_until || self.muted || !self.can_speak() {
+            return Ok(None);
+        }
+        let due = match absent {
+            None => true,
+            Some(absent) => greet.absence.map_or(false, |absence| absent >= absence),
+        };
+        if !due
+            || !should_send(
+                "",
+                &mut self.last_sent_greeting,
+                "",
+                greet.cooldown,
+                greet.chance,
+            )
+        {
+            return Ok(None);
+        }
+        Ok(Some(match &greet.template {
+            Some(template) => template.replace("{nick}", nick),
+            None => match brain.generate(nick)? {
+                Some(line) => format!("{nick}: {line}"),
+                None => format!("welcome, {nick}!"),
+            },
+        }))
+    }
+
+    // how long a human would take to type response, zero if typing isn't simulated in this room
+    fn typing_delay(&self, response: &str) -> Duration {
+        if self.typing_wpm == 0 {
+            return Duration::ZERO;
+        }
+        let words = response.split_whitespace().count() as f64;
+        let secs = words * 60.0 / self.typing_wpm as f64 * jitter(self.typing_jitter);
+        Duration::from_secs_f64(secs.clamp(0.0, MAX_TYPING_SECONDS))
+    }
+
+    fn new(
+        account: usize,
+        nick: String,
+        jid: FullJid,
+        chain_indices: Vec<usize>,
+        cfg: &RoomConfig,
+    ) -> Self {
         let long_ago = Instant::now().sub(Duration::from_secs(99999));
         Self {
+            account,
             nick,
             chain_indices,
             jid,
+            password: cfg.password.clone(),
+            aliases: cfg.aliases.clone().unwrap_or_default(),
+            threaded_replies: cfg.threaded_replies.unwrap_or(true),
+            typing_wpm: cfg.typing_wpm.unwrap_or(200),
+            typing_jitter: cfg.typing_jitter.unwrap_or(0.3),
+            chattiness: cfg.chattiness.unwrap_or(0.01),
+            muted: false,
+            bookmarked: false,
+            admins: cfg
+                .admins
+                .iter()
+                .flatten()
+                .filter_map(|admin| admin.parse().ok())
+                .collect(),
+            admin_occupant_ids: cfg.admin_occupant_ids.clone().unwrap_or_default(),
+            affiliation_admins: cfg.affiliation_admins.unwrap_or(false),
+            occupants: HashMap::new(),
+            joined: false,
+            greet: cfg.greet.unwrap_or(false).then(|| Greet {
+                template: cfg.greeting.clone(),
+                absence: cfg.greet_absence_hours.map(|hours| hours * 3600),
+                cooldown: cfg.greet_cooldown.unwrap_or(300),
+                chance: cfg.greet_chance.unwrap_or(1.0),
+            }),
+            subject: None,
+            last_heard: None,
+            set_topic: cfg.set_topic.unwrap_or(false),
+            create: cfg.create.clone(),
+            clean: clean::Clean {
+                quotes: cfg.strip_quotes.unwrap_or(true),
+                styling: cfg.strip_styling.unwrap_or(true),
+                attachments: cfg.strip_attachments.unwrap_or(true),
+            },
+            recent_joins: VecDeque::new(),
+            greet_quiet_until: long_ago,
             last_sent_jabber: long_ago,
             last_sent_dad: long_ago,
             last_sent_random: long_ago,
+            last_sent_greeting: long_ago,
+        }
+    }
+}
+
+struct Occupant {
+    // only if the room shows it to us
+    jid: Option<BareJid>,
+    role: Role,
+    affiliation: Affiliation,
+    // XEP-0421, stable across nick changes
+    occupant_id: Option<String>,
+}
+
+// whoever sent a message, as far as we can tell
+#[derive(Default)]
+struct Sender {
+    jid: Option<BareJid>,
+    // the room the message was sent in, None for one to one messages
+    room: Option<BareJid>,
+    // these only exist for messages sent in a room, and only count for that room
+    affiliation: Option<Affiliation>,
+    occupant_id: Option<String>,
+}
+
+impl Sender {
+    // stable ways of telling who sent a message no matter their nick, the most reliable first
+    fn identities(&self) -> Vec<String> {
+        let jid = self.jid.as_ref().map(|jid| format!("xmpp:{jid}"));
+        let occupant_id = self
+            .occupant_id
+            .as_ref()
+            .zip(self.room.as_ref())
+            .map(|(id, room)| format!("occupant-id:{room}:{id}"));
+        jid.into_iter().chain(occupant_id).collect()
+    }
+}
+
+// everything ash knows, shared between the event loop and command handlers
+struct Ash {
+    // everything ash is connected as, never empty
+    accounts: Vec<Account>,
+    // the account whatever's being handled came in on, and where anything said back goes
+    current: usize,
+    // stanzas that have to go out on a particular account, sent once the current event is handled
+    outbox: Vec<(usize, Element)>,
+    // keyed by node, domain and account, several accounts in the same room are separate personas
+    rooms: HashMap<(String, String, usize), Room>,
+    brain: Vec<Brain>,
+    conn: Connection,
+    admins: Vec<BareJid>,
+    // besides admins, whose invitations to rooms we accept
+    inviters: Vec<BareJid>,
+    // settings for rooms that don't come from ash.toml
+    room_template: RoomConfig,
+    // by language, chains messages in it are learned into and answered from, in place of the room's
+    lang_chains: HashMap<String, Vec<usize>>,
+    // our presence status, with {words} replaced by how many words we know
+    status: String,
+    // nothing is said anywhere, though everything is still learned and commands still work
+    maintenance: bool,
+}
+
+impl Ash {
+    fn account(&self) -> &Account {
+        &self.accounts[self.current]
+    }
+
+    // adds a room to be joined by account, returns the occupant jid to join it as
+    fn add_room(&mut self, cfg: &RoomConfig, account: usize) -> Result<FullJid> {
+        let nick = cfg
+            .nick
+            .clone()
+            .unwrap_or_else(|| self.accounts[account].nick.clone());
+        let jid: BareJid = cfg.room.parse()?;
+        let (node, domain) = room_key(&jid)?;
+        let key = (node, domain, account);
+        let jid = jid.with_resource(&nick);
+        let mut chain_indices = cfg.chain_indices.clone().unwrap_or_else(|| vec![0]);
+        // always push everything to 0
+        if !chain_indices.contains(&0) {
+            chain_indices.push(0);
+        }
+        let max = *chain_indices
+            .iter()
+            .max()
+            .expect("must exist due to above push");
+        if max >= self.brain.len() {
+            self.brain.resize(max + 1, Brain::new());
+        }
+        self.rooms.insert(
+            key,
+            Room::new(account, nick, jid.clone(), chain_indices, cfg),
+        );
+        Ok(jid)
+    }
+
+    // the current account's persona in the room at jid
+    fn key(&self, jid: &BareJid) -> Option<(String, String, usize)> {
+        let (node, domain) = room_key(jid).ok()?;
+        Some((node, domain, self.current))
+    }
+
+    fn room_mut(&mut self, jid: &BareJid) -> Option<&mut Room> {
+        let key = self.key(jid)?;
+        self.rooms.get_mut(&key)
+    }
+
+    fn remove_room(&mut self, jid: &BareJid) -> Option<Room> {
+        let key = self.key(jid)?;
+        self.rooms.remove(&key)
+    }
+
+    // every account's persona in a room
+    fn personas<'a>(&'a self, node: &'a str, domain: &'a str) -> impl Iterator<Item = &'a Room> {
+        self.rooms
+            .iter()
+            .filter(move |((n, d, _), _)| n == node && d == domain)
+            .map(|(_, room)| room)
+    }
+
+    // whether nick is one of our personas, who are never learned from, greeted or answered
+    fn is_persona(&self, node: &str, domain: &str, nick: &str) -> bool {
+        self.personas(node, domain).any(|room| room.nick == nick)
+    }
+
+    // the account whose persona learns from a room and greets in it, on behalf of all of them, so
+    // nothing is learned or said twice
+    fn learner(&self, node: &str, domain: &str) -> Option<usize> {
+        self.personas(node, domain).map(|room| room.account).min()
+    }
+
+    // every chain any persona in a room writes to, each once
+    fn chains(&self, node: &str, domain: &str) -> Vec<usize> {
+        let mut chains: Vec<usize> = self
+            .personas(node, domain)
+            .flat_map(|room| room.chain_indices.iter().copied())
+            .collect();
+        chains.sort_unstable();
+        chains.dedup();
+        chains
+    }
+
+    // the chains a language of its own is learned into instead of the room's, matching en-US to
+    // en if only en has any
+    fn lang_chains(&self, lang: &str) -> Option<&Vec<usize>> {
+        self.lang_chains
+            .get(lang)
+            .or_else(|| self.lang_chains.get(lang.split('-').next()?))
+    }
+
+    // every chain something in lang said in a room is learned into
+    fn chains_for(&self, node: &str, domain: &str, lang: &str) -> Vec<usize> {
+        match self.lang_chains(lang) {
+            Some(chains) => chains.clone(),
+            None => self.chains(node, domain),
+        }
+    }
+
+    // removes the current account's persona from a room, leaving it, returns whether it was there
+    fn part(&mut self, jid: &BareJid) -> bool {
+        match self.remove_room(jid) {
+            Some(room) => {
+                self.outbox.push((room.account, make_leave(room.jid)));
+                true
+            }
+            None => false,
+        }
+    }
+
+    // adds a room for the current account and remembers it for next time, returns the join to send
+    fn join_room(&mut self, cfg: &RoomConfig) -> Result<Element> {
+        // anyone who can type a command can pick indices, so they can only add one new chain,
+        // rather than make us allocate as many as they like
+        let chains = self.brain.len();
+        if let Some(i) = cfg.chain_indices.iter().flatten().find(|i| **i > chains) {
+            bail!("chain index {i} is too high, the next new one would be {chains}");
+        }
+        let jid = self.add_room(cfg, self.current)?;
+        self.save_room(cfg, self.current)?;
+        Ok(make_join(jid, cfg.password.clone()))
+    }
+
+    // leaves a room and forgets it was ever joined at runtime, returns whether we were in it
+    fn leave_room(&mut self, jid: &BareJid) -> Result<bool> {
+        if !self.part(jid) {
+            return Ok(false);
         }
+        self.conn.execute(
+            "DELETE FROM room WHERE jid = ? AND ifnull(account, ?) = ?",
+            [
+                jid.to_string(),
+                self.accounts[0].id.clone(),
+                self.account().id.clone(),
+            ],
+        )?;
+        Ok(true)
+    }
+
+    // throws away every chain and learns everything in the database again
+    fn rebuild_chains(&mut self) -> Result<()> {
+        let mut brain = vec![Brain::new(); self.brain.len()];
+        let mut chains = HashMap::new();
+        for (node, domain, _) in self.rooms.keys() {
+            chains
+                .entry((node.clone(), domain.clone()))
+                .or_insert_with(|| self.chains(node, domain));
+        }
+        let mut stmt = self
+            .conn
+            .prepare("SELECT node, domain, msg, lang from msg;")?;
+        let mut rows = stmt.query([])?;
+        while let Some(row) = rows.next()? {
+            let node: String = row.get(0)?;
+            let domain: String = row.get(1)?;
+            let msg: String = row.get(2)?;
+            let lang: Option<String> = row.get(3)?;
+            //println!("Found msg: {node}@{domain} - {msg}");
+            if let Some(chains) = lang.as_deref().and_then(|lang| self.lang_chains(lang)) {
+                for x in chains {
+                    brain[*x].ingest(&msg);
+                }
+            } else if let Some(chains) = chains.get(&(node, domain)) {
+                for x in chains {
+                    brain[*x].ingest(&msg);
+                }
+            } else {
+                // for now we are going to put *everything* in idx 0
+                brain[0].ingest(&msg);
+            }
+        }
+        self.brain = brain;
+        Ok(())
+    }
+
+    // rooms joined at runtime, which are joined again on startup, with the id of the account
+    // that joined them
+    fn saved_rooms(&self) -> Result<Vec<(RoomConfig, Option<String>)>> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT jid, nick, chain_indices, password, account from room;")?;
+        let mut rows = stmt.query([])?;
+        let mut rooms = Vec::new();
+        while let Some(row) = rows.next()? {
+            let chain_indices: Option<String> = row.get(2)?;
+            let room = RoomConfig {
+                room: row.get(0)?,
+                nick: row.get(1)?,
+                chain_indices: chain_indices
+                    .map(|c| {
+                        c.split(',')
+                            .map(|i| i.parse::<usize>())
+                            .collect::<Result<_, _>>()
+                    })
+                    .transpose()?,
+                password: row.get(3)?,
+                ..Default::default()
+            };
+            rooms.push((room, row.get(4)?));
+        }
+        Ok(rooms)
+    }
+
+    fn save_room(&self, cfg: &RoomConfig, account: usize) -> Result<()> {
+        let chain_indices = cfg.chain_indices.as_ref().map(|c| {
+            c.iter()
+                .map(|i| i.to_string())
+                .collect::<Vec<_>>()
+                .join(",")
+        });
+        let jid: BareJid = cfg.room.parse()?;
+        self.conn.execute(
+            "INSERT OR REPLACE INTO room (jid, nick, chain_indices, password, account) values (?, ?, ?, ?, ?)",
+            params![
+                jid.to_string(),
+                cfg.nick,
+                chain_indices,
+                cfg.password,
+                self.accounts[account].id
+            ],
+        )?;
+        Ok(())
+    }
+
+    // forgets every message sender ever sent, in every room and under every nick we can tie to
+    // them, returns how many
+    fn forget(&mut self, sender: &Sender) -> Result<usize> {
+        let mut forgotten = 0;
+        for identity in sender.identities() {
+            forgotten += self
+                .conn
+                .execute("DELETE FROM msg WHERE identity = ?", [&identity])?;
+            // stored before we knew who anybody was, so nicks are all we have to go on
+            if let Some(room) = &sender.room {
+                let (node, domain) = room_key(room)?;
+                forgotten += self.conn.execute(
+                    "DELETE FROM msg WHERE identity IS NULL AND node = ?1 AND domain = ?2 AND nick IN
+                        (SELECT nick FROM nick WHERE node = ?1 AND domain = ?2 AND identity = ?3)",
+                    [node, domain, identity],
+                )?;
+            }
+        }
+        if forgotten > 0 {
+            self.rebuild_chains()?;
+        }
+        Ok(forgotten)
+    }
+
+    // forgets every message learned in room containing text, returns how many
+    fn unlearn(&mut self, room: &BareJid, text: &str) -> Result<usize> {
+        let (node, domain) = room_key(room)?;
+        let forgotten = self.conn.execute(
+            "DELETE FROM msg WHERE node = ? AND domain = ? AND instr(msg, ?) > 0",
+            [node, domain, text.to_string()],
+        )?;
+        if forgotten > 0 {
+            self.rebuild_chains()?;
+        }
+        Ok(forgotten)
+    }
+
+    // keeps track of who's in our rooms, returns a greeting to send if someone new joined
+    fn handle_presence(&mut self, presence: Presence) -> Option<Element> {
+        let from = match presence.from {
+            Some(Jid::Full(from)) => from,
+            _ => return None,
+        };
+        let key = self.key(&from.clone().into())?;
+        // our other personas come and go like anyone else, but aren't anyone to greet
+        let persona = self.is_persona(&key.0, &key.1, &from.resource);
+        let learner = self.learner(&key.0, &key.1) == Some(self.current);
+        let room = self.rooms.get_mut(&key)?;
+        let muc_user = presence
+            .payloads
+            .iter()
+            .find_map(|payload| MucUser::try_from(payload.clone()).ok());
+        let item = muc_user
+            .as_ref()
+            .and_then(|muc_user| muc_user.items.first());
+        let self_presence = from.resource == room.nick
+            || muc_user.as_ref().map_or(false, |muc_user| {
+                muc_user.status.contains(&Status::SelfPresence)
+            });
+        match presence.type_ {
+            PresenceType::None => {}
+            PresenceType::Unavailable => {
+                if self_presence {
+                    room.joined = false;
+                }
+                if learner && !persona {
+                    let who = seen_as(&room.sender(&from.resource), &from.resource);
+                    if let Err(e) = mark_seen(&self.conn, &key.0, &key.1, &who) {
+                        println!("failed to record {who} leaving {}: {e}", from);
+                    }
+                }
+                let occupant = room.occupants.remove(&from.resource);
+                // nick changes look like leaving, with the new nick, then joining with it
+                let new_nick = muc_user
+                    .as_ref()
+                    .filter(|muc_user| muc_user.status.contains(&Status::NewNick))
+                    .and_then(|_| item)
+                    .and_then(|item| item.nick.clone());
+                if let (Some(occupant), Some(new_nick)) = (occupant, new_nick) {
+                    room.occupants.insert(new_nick, occupant);
+                }
+                return None;
+            }
+            PresenceType::Error => {
+                println!("presence error from {from}: {:?}", presence.payloads);
+                return None;
+            }
+            _ => return None,
+        }
+        let occupant_id = presence
+            .payloads
+            .iter()
+            .find(|payload| payload.is("occupant-id", NS_OCCUPANT_ID))
+            .and_then(|occupant_id| occupant_id.attr("id"))
+            .map(|id| id.to_string());
+        // anything after someone's first presence is just a status change
+        let joining = !room.occupants.contains_key(&from.resource);
+        room.occupants.insert(
+            from.resource.clone(),
+            Occupant {
+                jid: item.and_then(|i| i.jid.clone()).map(BareJid::from),
+                role: item.map_or(Role::None, |i| i.role.clone()),
+                affiliation: item.map_or(Affiliation::None, |i| i.affiliation.clone()),
+                occupant_id,
+            },
+        );
+        if self_presence {
+            // the room echoes every presence we send it, only the first means we just got in
+            if room.joined {
+                return None;
+            }
+            room.joined = true;
+            // rooms that didn't exist yet stay locked until their owner, us, configures them
+            let created = muc_user.as_ref().map_or(false, |muc_user| {
+                muc_user.status.contains(&Status::RoomHasBeenCreated)
+            });
+            if created {
+                let room_jid = BareJid::from(from.clone());
+                println!("created {room_jid}");
+                let config = owner::make_config(room_jid, room.create.as_ref());
+                self.outbox.push((self.current, config));
+            }
+            return self.presence_in(&from.into());
+        }
+        if !joining || persona || !learner {
+            return None;
+        }
+        let who = seen_as(&room.sender(&from.resource), &from.resource);
+        let absent = match mark_seen(&self.conn, &key.0, &key.1, &who) {
+            Ok(absent) => absent,
+            Err(e) => {
+                println!("failed to record {who} joining {}: {e}", from);
+                return None;
+            }
+        };
+        // they were already here when we joined
+        if !room.joined || self.maintenance {
+            return None;
+        }
+        let brain = &mut self.brain[room.chain_indices[0]];
+        match room.greeting(&from.resource, absent, brain) {
+            Ok(greeting) => greeting.map(|greeting| make_reply(Jid::Bare(from.into()), &greeting)),
+            Err(e) => {
+                println!("failed to greet {from}: {e}");
+                None
+            }
+        }
+    }
+
+    // remembers a room's subject, learning from it when it changes, returns whether message was a
+    // subject change at all
+    fn handle_subject(&mut self, message: &Message) -> Result<bool> {
+        // per XEP-0045 only a subject without a body changes it
+        if message.type_ != MessageType::Groupchat || !message.bodies.is_empty() {
+            return Ok(false);
+        }
+        let (lang, subject) = match message.get_best_subject(vec![]) {
+            Some((lang, Subject(subject))) => (lang, subject.trim().to_string()),
+            None => return Ok(false),
+        };
+        let from = match &message.from {
+            Some(from) => from,
+            None => return Ok(false),
+        };
+        // anyone can send a subject, only our own rooms' are learned
+        let (node, domain, _) = match self.key(&bare(from)) {
+            Some(key) if self.rooms.contains_key(&key) => key,
+            _ => return Ok(false),
+        };
+        let chains = self.chains_for(&node, &domain, &lang);
+        let persona = match from {
+            Jid::Full(from) => self.is_persona(&node, &domain, &from.resource),
+            Jid::Bare(_) => false,
+        };
+        let room = match self
+            .rooms
+            .get_mut(&(node.clone(), domain.clone(), self.current))
+        {
+            Some(room) => room,
+            None => return Ok(false),
+        };
+        room.subject = Some(subject.clone()).filter(|subject| !subject.is_empty());
+        // rooms repeat their subject every time we join
+        let last: Option<String> = self
+            .conn
+            .query_row(
+                "SELECT subject FROM subject WHERE node = ? AND domain = ?",
+                [&node, &domain],
+                |row| row.get(0),
+            )
+            .ok();
+        if last.as_ref() == Some(&subject) {
+            return Ok(true);
+        }
+        println!("subject of {}: {subject}", bare(from));
+        self.conn.execute(
+            "INSERT OR REPLACE INTO subject (node, domain, subject) values (?, ?, ?)",
+            [&node, &domain, &subject],
+        )?;
+        // set by the room itself rather than an occupant
+        let nick = match from {
+            Jid::Full(from) => from.resource.as_str(),
+            Jid::Bare(_) => return Ok(true),
+        };
+        let learned = clean::clean(&subject, &[], &room.clean);
+        if learned.is_empty() || persona {
+            return Ok(true);
+        }
+        store_message(
+            &self.conn,
+            &node,
+            &domain,
+            nick,
+            &learned,
+            &lang,
+            &room.sender(nick),
+        )?;
+        for x in &chains {
+            self.brain[*x].ingest(&learned);
+        }
+        Ok(true)
+    }
+
+    fn status(&self) -> String {
+        let words = thousands(self.brain[0].stats().get_total_words());
+        self.status.replace("{words}", &words)
+    }
+
+    // account's presence everywhere, to the server and every room it's in
+    fn presences(&self, account: usize) -> Vec<Element> {
+        // components have no account to be present on
+        (!self.accounts[account].component)
+            .then(|| make_presence(self, account, None))
+            .into_iter()
+            .chain(
+                self.rooms
+                    .values()
+                    .filter(|room| room.account == account && room.joined)
+                    .map(|room| make_presence(self, account, Some(room))),
+            )
+            .collect()
+    }
+
+    // our presence in the room at jid, if we're in it
+    fn presence_in(&self, jid: &BareJid) -> Option<Element> {
+        let room = self.rooms.get(&self.key(jid)?)?;
+        room.joined
+            .then(|| make_presence(self, room.account, Some(room)))
+    }
+
+    // sends our presence in the room at jid again, after something it shows has changed
+    fn refresh_presence(&mut self, jid: &BareJid) {
+        if let Some(presence) = self.presence_in(jid) {
+            self.outbox.push((self.current, presence));
+        }
+    }
+
+    // sends our presence everywhere again, after something all of them show has changed
+    fn refresh_presences(&mut self) {
+        for account in 0..self.accounts.len() {
+            let presences = self.presences(account);
+            self.outbox
+                .extend(presences.into_iter().map(|presence| (account, presence)));
+        }
+    }
+
+    fn is_admin(&self, jid: &Jid) -> bool {
+        self.admins.contains(&bare(jid))
+    }
+
+    // whether sender may run privileged commands acting on target, or on ash as a whole when
+    // target is None, which only global admins can
+    fn is_privileged(&self, sender: &Sender, target: Option<&BareJid>) -> bool {
+        if let Some(jid) = &sender.jid {
+            if self.admins.contains(jid) {
+                return true;
+            }
+        }
+        let room = match target.and_then(|t| self.rooms.get(&self.key(t)?)) {
+            Some(room) => room,
+            None => return false,
+        };
+        if let Some(jid) = &sender.jid {
+            if room.admins.contains(jid) {
+                return true;
+            }
+        }
+        // who someone is in one room says nothing about who they are in another
+        if sender.room.as_ref() != target {
+            return false;
+        }
+        let by_occupant_id = sender
+            .occupant_id
+            .as_ref()
+            .map_or(false, |id| room.admin_occupant_ids.contains(id));
+        let by_affiliation = room.affiliation_admins
+            && matches!(
+                sender.affiliation,
+                Some(Affiliation::Owner) | Some(Affiliation::Admin)
+            );
+        by_occupant_id || by_affiliation
+    }
+
+    fn can_invite(&self, jid: &Jid) -> bool {
+        self.is_admin(jid) || self.inviters.contains(&bare(jid))
+    }
+
+    // whether to accept invite, the server vouches for who sent a direct one, but whoever sends a
+    // mediated one writes its inviter themselves, so only a room service ash already uses is
+    // believed about it
+    fn trusts_invite(&self, invite: &Invite) -> bool {
+        if invite.mediated
+            && !self
+                .rooms
+                .keys()
+                .any(|(_, domain, _)| *domain == invite.room.domain)
+        {
+            return false;
+        }
+        self.can_invite(&invite.inviter)
+    }
+}
+
+// saves a message to learn from, along with who sent it so it can be found again whatever nick
+// they used
+fn store_message(
+    conn: &Connection,
+    node: &str,
+    domain: &str,
+    nick: &str,
+    msg: &str,
+    lang: &str,
+    sender: &Sender,
+) -> Result<()> {
+    let identities = sender.identities();
+    // no language means the stream's, whatever that was
+    let lang = Some(lang).filter(|lang| !lang.is_empty());
+    conn.execute(
+        "INSERT INTO msg (node, domain, nick, msg, identity, lang) values (?, ?, ?, ?, ?, ?)",
+        params![node, domain, nick, msg, identities.first(), lang],
+    )?;
+    for identity in &identities {
+        conn.execute(
+            "INSERT OR IGNORE INTO nick (node, domain, nick, identity) values (?, ?, ?, ?)",
+            [node, domain, nick, identity.as_str()],
+        )?;
+    }
+    Ok(())
+}
+
+// the most reliable way of telling who nick is, for remembering when they were last around
+fn seen_as(sender: &Sender, nick: &str) -> String {
+    sender
+        .identities()
+        .into_iter()
+        .next()
+        .unwrap_or_else(|| format!("nick:{nick}"))
+}
+
+// records who as being in a room now, returns how many seconds ago they last were, None if never
+fn mark_seen(conn: &Connection, node: &str, domain: &str, who: &str) -> Result<Option<u64>> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64;
+    let last: Option<i64> = conn
+        .query_row(
+            "SELECT time FROM seen WHERE node = ? AND domain = ? AND who = ?",
+            [node, domain, who],
+            |row| row.get(0),
+        )
+        .ok();
+    conn.execute(
+        "INSERT OR REPLACE INTO seen (node, domain, who, time) values (?, ?, ?, ?)",
+        params![node, domain, who, now],
+    )?;
+    Ok(last.map(|last| now.saturating_sub(last).max(0) as u64))
+}
+
+fn bare(jid: &Jid) -> BareJid {
+    match jid {
+        Jid::Bare(jid) => jid.clone(),
+        Jid::Full(jid) => jid.clone().into(),
+    }
+}
+
+fn room_key(jid: &BareJid) -> Result<(String, String)> {
+    match jid.node {
+        Some(ref node) => Ok((node.clone(), jid.domain.clone())),
+        None => bail!("room jids must have local part: {jid}"),
     }
 }
 
 #[derive(Deserialize)]
 struct Config {
-    jid: String,
-    password: String,
+    // the first account, which can be left out if everything is in accounts
+    #[serde(flatten)]
+    account: AccountConfig,
+    // any others, sharing chains, the database and everything below with the first
+    #[serde(default)]
+    accounts: Vec<AccountConfig>,
     db: Option<String>,
+    // for accounts that don't set their own
     nick: Option<String>,
+    admins: Option<Vec<String>>,
+    inviters: Option<Vec<String>>,
+    room_template: Option<RoomConfig>,
+    lang_chains: Option<HashMap<String, Vec<usize>>>,
+    status: Option<String>,
+    status_minutes: Option<u64>,
+}
+
+#[derive(Deserialize, Default)]
+struct AccountConfig {
+    // either a client account
+    jid: Option<String>,
+    password: Option<String>,
+    // or a XEP-0114 component, connecting to connection.host and port
+    component_jid: Option<String>,
+    secret: Option<String>,
+    nick: Option<String>,
+    bookmarks: Option<bool>,
+    profile: Option<ProfileConfig>,
+    connection: Option<ConnectionConfig>,
+    #[serde(default)]
     rooms: Vec<RoomConfig>,
 }
 
+#[derive(Deserialize, Default)]
+struct ConnectionConfig {
+    // connect here instead of looking up SRV records for the jid's domain
+    host: Option<String>,
+    port: Option<u16>,
+    resource: Option<String>,
+    // tokio-xmpp always connects with STARTTLS and only trusts the webpki roots, setting any of
+    // these logs in through login.rs instead
+    direct_tls: Option<bool>,
+    ca_file: Option<String>,
+    pin_sha256: Option<String>,
+    // XEP-0198, resuming the stream after reconnecting, which also logs in through login.rs
+    stream_management: Option<bool>,
+    // socks5h://host:port, which also logs in through login.rs, since tokio-xmpp opens its own TCP
+    // connections
+    proxy: Option<String>,
+}
+
 #[derive(Deserialize)]
+struct ProfileConfig {
+    // path to a png, jpeg, gif or webp image
+    avatar: Option<String>,
+    name: Option<String>,
+    url: Option<String>,
+    description: Option<String>,
+}
+
+#[derive(Deserialize, Default, Clone)]
 struct RoomConfig {
+    // not set in room_template
+    #[serde(default)]
     room: String,
     chain_indices: Option<Vec<usize>>,
     nick: Option<String>,
+    password: Option<String>,
+    aliases: Option<Vec<String>>,
+    threaded_replies: Option<bool>,
+    typing_wpm: Option<u32>,
+    typing_jitter: Option<f64>,
+    chattiness: Option<f64>,
+    admins: Option<Vec<String>>,
+    admin_occupant_ids: Option<Vec<String>>,
+    affiliation_admins: Option<bool>,
+    greet: Option<bool>,
+    greeting: Option<String>,
+    greet_absence_hours: Option<u64>,
+    greet_cooldown: Option<u64>,
+    greet_chance: Option<f64>,
+    set_topic: Option<bool>,
+    create: Option<CreateConfig>,
+    strip_quotes: Option<bool>,
+    strip_styling: Option<bool>,
+    strip_attachments: Option<bool>,
+}
+
+#[derive(Deserialize, Default, Clone)]
+struct CreateConfig {
+    name: Option<String>,
+    description: Option<String>,
+    persistent: Option<bool>,
+    members_only: Option<bool>,
 }
 
 fn parse_cfg<P: AsRef<Path>>(path: P) -> Result<Config> {
@@ -110,64 +1131,73 @@ fn parse_cfg<P: AsRef<Path>>(path: P) -> Result<Config> {
 async fn main() -> Result<()> {
     env_logger::init();
 
-    let first_arg = args().nth(1);
+    let mut args = args().skip(1).peekable();
+    // create the accounts in the config rather than logging in to them
+    let register = args.next_if_eq("register").is_some();
+    let first_arg = args.next();
 
-    let cfg = match first_arg.as_deref() {
+    let (path, cfg) = match first_arg.as_deref() {
         Some("-h") | Some("--help") => {
-            die!("usage: ash [/path/to/config.toml]")
+            die!("usage: ash [register] [/path/to/config.toml]")
         }
-        Some(config) => parse_cfg(config).die("provided config cannot be found/parsed"),
-        None => parse_cfg(
-            dirs::config_dir()
+        Some(config) => (
+            PathBuf::from(config),
+            parse_cfg(config).die("provided config cannot be found/parsed"),
+        ),
+        None => {
+            let home = dirs::config_dir()
                 .die("cannot find home directory")
-                .join("ash.toml"),
-        )
-        .or_else(|_| parse_cfg("/etc/ash/ash.toml"))
-        .die("valid config file not found"),
+                .join("ash.toml");
+            match parse_cfg(&home) {
+                Ok(cfg) => (home, cfg),
+                Err(_) => (
+                    PathBuf::from("/etc/ash/ash.toml"),
+                    parse_cfg("/etc/ash/ash.toml").die("valid config file not found"),
+                ),
+            }
+        }
     };
 
-    if cfg.rooms.is_empty() {
-        die!("no rooms specified!");
+    if register {
+        return register::register(&path, &cfg).await;
     }
 
-    let mut rooms: HashMap<(String, String), Room> = HashMap::with_capacity(cfg.rooms.len());
-
-    let mut max_idx = 0;
-    let jid: BareJid = cfg.jid.parse()?;
-    for room in cfg.rooms {
-        let nick = room
-            .nick
-            .as_ref()
-            .or(cfg.nick.as_ref())
-            .or(jid.node.as_ref())
-            .map(|s| s.as_str())
-            .unwrap_or("ash")
-            .to_string();
-        let jid: BareJid = room.room.parse()?;
-        let jid = jid.with_resource(&nick);
-        let mut chain_indices = room.chain_indices.unwrap_or_else(|| vec![0]);
-        // always push everything to 0
-        if !chain_indices.contains(&0) {
-            chain_indices.push(0);
-        }
-        let max = *chain_indices
-            .iter()
-            .max()
-            .expect("must exist due to above push");
-        if max > max_idx {
-            max_idx = max;
+    let mut account_cfgs = cfg.accounts;
+    if cfg.account.jid.is_some() || cfg.account.component_jid.is_some() {
+        account_cfgs.insert(0, cfg.account);
+    } else if !cfg.account.rooms.is_empty() {
+        die!("rooms need a jid or component_jid to join them with");
+    }
+    if account_cfgs.is_empty() {
+        die!("set one of jid or component_jid");
+    }
+    if account_cfgs
+        .iter()
+        .all(|account| account.rooms.is_empty() && !account.bookmarks.unwrap_or(false))
+    {
+        die!("no rooms specified!");
+    }
+    let mut accounts: Vec<Account> = Vec::with_capacity(account_cfgs.len());
+    for account in &account_cfgs {
+        let account = Account::connect(account, cfg.nick.as_ref())?;
+        // the server would only let one of them stay connected
+        if accounts.iter().any(|other| other.id == account.id) {
+            die!("{} is listed twice, give each its own resource", account.id);
         }
-        rooms.insert(
-            (
-                jid.node
-                    .as_ref()
-                    .die("room jids must have local part")
-                    .clone(),
-                jid.domain.clone(),
-            ),
-            Room::new(nick, jid, chain_indices),
-        );
+        accounts.push(account);
     }
+    let admins = cfg
+        .admins
+        .iter()
+        .flatten()
+        .map(|admin| admin.parse())
+        .collect::<Result<_, _>>()?;
+    let inviters = cfg
+        .inviters
+        .iter()
+        .flatten()
+        .map(|inviter| inviter.parse())
+        .collect::<Result<_, _>>()?;
 
     let conn = Connection::open(cfg.db.as_deref().unwrap_or("ash.db"))?;
 
@@ -182,39 +1212,307 @@ async fn main() -> Result<()> {
         (), // empty list of parameters.
     )?;
 
-    let mut brain = vec![Brain::new(); max_idx + 1];
-    let mut stmt = conn.prepare("SELECT node, domain, msg from msg;")?;
-    let mut rows = stmt.query([])?;
-    while let Some(row) = rows.next()? {
-        let node: String = row.get(0)?;
-        let domain: String = row.get(1)?;
-        let msg: String = row.get(2)?;
-        //println!("Found msg: {node}@{domain} - {msg}");
-        if let Some(room) = rooms.get(&(node, domain)) {
-            for x in &room.chain_indices {
-                brain[*x].ingest(&msg);
+    // who sent each message, added after msg was first created
+    if conn.prepare("SELECT identity FROM msg LIMIT 0").is_err() {
+        conn.execute("ALTER TABLE msg ADD COLUMN identity TEXT", ())?;
+    }
+    conn.execute(
+        "CREATE INDEX IF NOT EXISTS msg_identity ON msg (identity)",
+        (),
+    )?;
+
+    // the xml:lang of each message, added after msg was first created
+    if conn.prepare("SELECT lang FROM msg LIMIT 0").is_err() {
+        conn.execute("ALTER TABLE msg ADD COLUMN lang TEXT", ())?;
+    }
+
+    // every nick each identity has been seen using in each room
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS nick (
+            node  TEXT NOT NULL,
+            domain  TEXT NOT NULL,
+            nick  TEXT NOT NULL,
+            identity  TEXT NOT NULL,
+            PRIMARY KEY (node, domain, nick, identity)
+        )",
+        (),
+    )?;
+
+    // when each identity was last in each room, so returning occupants aren't greeted as new
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS seen (
+            node  TEXT NOT NULL,
+            domain  TEXT NOT NULL,
+            who  TEXT NOT NULL,
+            time  INTEGER NOT NULL,
+            PRIMARY KEY (node, domain, who)
+        )",
+        (),
+    )?;
+
+    // the last subject of each room, so the one sent on every join isn't learned again
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS subject (
+            node  TEXT NOT NULL,
+            domain  TEXT NOT NULL,
+            subject  TEXT NOT NULL,
+            PRIMARY KEY (node, domain)
+        )",
+        (),
+    )?;
+
+    // rooms joined at runtime, by the id of the account that joined them
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS room (
+            jid  TEXT NOT NULL,
+            account  TEXT NOT NULL,
+            nick  TEXT,
+            chain_indices  TEXT,
+            password  TEXT,
+            PRIMARY KEY (jid, account)
+        )",
+        (),
+    )?;
+
+    // added once there could be more than one account, rooms saved before then were joined by the
+    // first, and older databases still only keep one account for each room
+    if conn.prepare("SELECT account FROM room LIMIT 0").is_err() {
+        conn.execute("ALTER TABLE room ADD COLUMN account TEXT", ())?;
+    }
+
+    let mut ash = Ash {
+        accounts,
+        current: 0,
+        outbox: Vec::new(),
+        rooms: HashMap::new(),
+        brain: vec![Brain::new()],
+        conn,
+        admins,
+        inviters,
+        room_template: cfg.room_template.clone().unwrap_or_default(),
+        lang_chains: cfg
+            .lang_chains
+            .clone()
+            .unwrap_or_default()
+            .into_iter()
+            .map(|(lang, mut chains)| {
+                // always push everything to 0, like rooms
+                if !chains.contains(&0) {
+                    chains.push(0);
+                }
+                (lang, chains)
+            })
+            .collect(),
+        status: cfg
+            .status
+            .clone()
+            .unwrap_or_else(|| "I know {words} words!".to_string()),
+        maintenance: false,
+    };
+    for (account, account_cfg) in account_cfgs.iter().enumerate() {
+        for room in &account_cfg.rooms {
+            // a second persona in the same room needs an account of its own to be in it with
+            let (node, domain) = room_key(&room.room.parse()?)?;
+            if ash.rooms.contains_key(&(node, domain, account)) {
+                die!(
+                    "{} is listed twice for the same account, give each persona its own account",
+                    room.room
+                );
             }
-        } else {
-            // for now we are going to put *everything* in idx 0
-            brain[0].ingest(&msg);
+            ash.add_room(room, account)?;
         }
     }
+    for (room, id) in ash.saved_rooms()? {
+        // rooms saved by an account since removed, or before there were accounts, go to the first
+        let account = ash
+            .accounts
+            .iter()
+            .position(|account| Some(&account.id) == id.as_ref())
+            .unwrap_or(0);
+        // ash.toml wins over anything saved for the same room
+        let (node, domain) = room_key(&room.room.parse()?)?;
+        if !ash.rooms.contains_key(&(node, domain, account)) {
+            ash.add_room(&room, account)?;
+        }
+    }
+    let most = ash
+        .lang_chains
+        .values()
+        .flatten()
+        .max()
+        .copied()
+        .unwrap_or(0);
+    if most >= ash.brain.len() {
+        ash.brain.resize(most + 1, Brain::new());
+    }
+    ash.rebuild_chains()?;
+
+    // replies waiting on a simulated typing delay come back through here to be sent
+    let (delayed_tx, mut delayed_rx) = mpsc::unbounded_channel();
 
-    let mut client = Client::new(&cfg.jid, &cfg.password)?;
-    client.set_reconnect(true);
+    // our status changes as we learn, so it's sent again every so often
+    let status_period = Duration::from_secs(cfg.status_minutes.unwrap_or(10).max(1) * 60);
+    let mut status_refresh =
+        tokio::time::interval_at(tokio::time::Instant::now() + status_period, status_period);
 
-    while let Some(event) = client.next().await {
-        if event.is_online() {
-            for room in rooms.values() {
-                let join = make_join(room.jid.clone());
-                client.send_stanza(join).await?;
+    loop {
+        // anything handling the last event had to say on another account
+        for (account, stanza) in std::mem::take(&mut ash.outbox) {
+            ash.accounts[account].send(stanza).await;
+        }
+        let nexts = ash
+            .accounts
+            .iter_mut()
+            .enumerate()
+            .map(|(account, a)| Box::pin(async move { (account, a.next().await) }));
+        let (account, event) = tokio::select! {
+            ((account, event), _, _) = select_all(nexts) => (account, event),
+            Some((account, stanza)) = delayed_rx.recv() => {
+                ash.accounts[account].send(stanza).await;
+                continue;
             }
-        } else if let Some(message) = event
-            .into_stanza()
-            .and_then(|stanza| Message::try_from(stanza).ok())
-        {
-            match (&message.from, message.bodies.get("")) {
-                (Some(ref from), Some(ref body)) => {
+            _ = status_refresh.tick() => {
+                let status = ash.status();
+                for account in 0..ash.accounts.len() {
+                    // accounts coming back online send it then
+                    let sent = &ash.accounts[account].status_sent;
+                    if !ash.accounts[account].online || status == *sent {
+                        continue;
+                    }
+                    for presence in ash.presences(account) {
+                        ash.accounts[account].send(presence).await;
+                    }
+                    ash.accounts[account].status_sent = status.clone();
+                }
+                continue;
+            }
+        };
+        ash.current = account;
+        if let Incoming::Online(Session::Resumed(h)) = event {
+            // rooms, presence and everything else carried on as they were
+            println!("{} resumed its session", ash.accounts[account].id);
+            for message in ash.accounts[account].sm.resume(h) {
+                ash.accounts[account].send(message).await;
+            }
+            continue;
+        }
+        if let Incoming::Online(Session::New(offered)) = event {
+            // first, so that everything after it is counted
+            let unacked = match ash.accounts[account].sm.start(offered) {
+                Some((enable, unacked)) => {
+                    ash.accounts[account].send(enable).await;
+                    unacked
+                }
+                None => vec![],
+            };
+            // needed for the server to send us bookmark notifications
+            if !ash.accounts[account].component {
+                let presence = make_presence(&ash, account, None);
+                ash.accounts[account].send(presence).await;
+            }
+            ash.accounts[account].status_sent = ash.status();
+            if ash.accounts[account].bookmarks {
+                let request = bookmarks::make_request(&mut ash.accounts[account]);
+                ash.accounts[account].send(request).await;
+            }
+            let requests: Vec<_> = ash.accounts[account]
+                .profile
+                .iter_mut()
+                .flat_map(|profile| profile.make_requests())
+                .collect();
+            for request in requests {
+                ash.accounts[account].send(request).await;
+            }
+            for room in ash
+                .rooms
+                .values_mut()
+                .filter(|room| room.account == account)
+            {
+                // we'll be sent everyone's presence again once we're back in
+                room.occupants.clear();
+                room.joined = false;
+                room.subject = None;
+                // ask for whatever we missed, it's only learned from, never answered
+                let history = match room.last_heard {
+                    Some(heard) if ash.accounts[account].sm.wanted => {
+                        History::new().with_seconds(heard.elapsed().as_secs() as u32)
+                    }
+                    _ => History::new().with_maxstanzas(0),
+                };
+                let join = make_join_with(room.jid.clone(), room.password.clone(), history);
+                ash.accounts[account].send(join).await;
+            }
+            for message in unacked {
+                ash.accounts[account].send(message).await;
+            }
+            continue;
+        }
+        let stanza = match event {
+            Incoming::Stanza(stanza) => stanza,
+            _ => continue,
+        };
+        if let Some(answers) = ash.accounts[account].sm.received(&stanza) {
+            for answer in answers {
+                ash.accounts[account].send(answer).await;
+            }
+            continue;
+        }
+        if stanza.is("iq", ns::DEFAULT_NS) {
+            for response in iq::handle_iq(stanza, &mut ash) {
+                ash.accounts[account].send(response).await;
+            }
+        } else if stanza.is("presence", ns::DEFAULT_NS) {
+            if let Ok(presence) = Presence::try_from(stanza) {
+                if let Some(greeting) = ash.handle_presence(presence) {
+                    ash.accounts[account].send(greeting).await;
+                }
+            }
+        } else if let Ok(message) = Message::try_from(stanza) {
+            if let Some(invite) = invites::parse_invite(&message) {
+                if ash.room_mut(&invite.room).is_some() {
+                    continue;
+                }
+                if !ash.trusts_invite(&invite) {
+                    println!(
+                        "declining invite to {} from {}",
+                        invite.room, invite.inviter
+                    );
+                    ash.accounts[account]
+                        .send(invites::make_decline(&invite))
+                        .await;
+                    continue;
+                }
+                println!(
+                    "accepting invite to {} from {}",
+                    invite.room, invite.inviter
+                );
+                let cfg = RoomConfig {
+                    room: invite.room.to_string(),
+                    password: invite.password.clone(),
+                    ..Default::default()
+                };
+                match ash.join_room(&cfg) {
+                    Ok(join) => ash.accounts[account].send(join).await,
+                    Err(e) => {
+                        println!("can't join {}: {e}", invite.room);
+                        ash.accounts[account]
+                            .send(invites::make_decline(&invite))
+                            .await;
+                    }
+                }
+                continue;
+            }
+            if ash.accounts[account].bookmarks {
+                for stanza in bookmarks::handle_event(&mut ash, &message) {
+                    ash.accounts[account].send(stanza).await;
+                }
+            }
+            if ash.handle_subject(&message)? {
+                continue;
+            }
+            // the body without a language, which is in the stream's, or failing that any of them
+            match (&message.from, message.get_best_body(vec![])) {
+                (Some(ref from), Some((lang, body))) => {
                     if message.type_ != MessageType::Error {
                         match from {
                             Jid::Full(FullJid {
@@ -222,43 +1520,175 @@ async fn main() -> Result<()> {
                                 domain,
                                 resource,
                             }) => {
-                                if let Some(room) =
-                                    rooms.get_mut(&(node.to_string(), domain.to_string()))
-                                {
-                                    let nick = &room.nick;
-                                    if resource == nick {
+                                let persona = ash.is_persona(node, domain, resource);
+                                let learner = ash.learner(node, domain) == Some(account);
+                                let chains = ash.chains_for(node, domain, &lang);
+                                // languages with chains of their own are answered from them
+                                let lang_chain = ash.lang_chains(&lang).map(|chains| chains[0]);
+                                if let Some(room) = ash.rooms.get_mut(&(
+                                    node.to_string(),
+                                    domain.to_string(),
+                                    account,
+                                )) {
+                                    room.last_heard = Some(Instant::now());
+                                    let chain = lang_chain.unwrap_or(room.chain_indices[0]);
+                                    // ourselves, or one of our other personas
+                                    if persona {
                                         continue;
                                     }
                                     let body = &body.0;
                                     println!("from: '{from}', body: {body}");
-                                    let response = if body.starts_with(nick) {
-                                        let body = body.trim_start_matches(nick);
-                                        let body = body.trim_start_matches([',', ':', ' ']);
+                                    // history caught up on after reconnecting, too late to answer
+                                    let delayed =
+                                        message.payloads.iter().any(|p| p.is("delay", ns::DELAY));
+                                    let addressed = if delayed {
+                                        None
+                                    } else {
+                                        room.addressed(body, &message)
+                                    };
+                                    let command = addressed
+                                        .as_ref()
+                                        .and_then(|_| room.commanded(body, &message));
+                                    if let Some(command) =
+                                        command.as_deref().and_then(commands::parse_text_command)
+                                    {
+                                        let room_jid = BareJid {
+                                            node: Some(node.to_string()),
+                                            domain: domain.to_string(),
+                                        };
+                                        let sender = room.sender(resource);
+                                        let mut stanzas = Vec::new();
+                                        let reply = commands::run_text_command(
+                                            &mut ash,
+                                            command,
+                                            &sender,
+                                            &mut stanzas,
+                                        );
+                                        ash.accounts[account]
+                                            .send(make_reply(Jid::Bare(room_jid), &reply))
+                                            .await;
+                                        for stanza in stanzas {
+                                            ash.accounts[account].send(stanza).await;
+                                        }
+                                        continue;
+                                    }
+                                    if addressed
+                                        .as_deref()
+                                        .map_or(false, |body| body.eq_ignore_ascii_case("topic"))
+                                        && !room.muted
+                                        && !ash.maintenance
+                                        && room.can_speak()
+                                    {
+                                        let room_jid = Jid::Bare(BareJid {
+                                            node: Some(node.to_string()),
+                                            domain: domain.to_string(),
+                                        });
+                                        let topic = room
+                                            .topic(&mut ash.brain[chain])?
+                                            .unwrap_or_else(|| {
+                                                "I don't know enough to come up with one"
+                                                    .to_string()
+                                            });
+                                        println!("topic: {topic}");
+                                        let stanza = if room.can_set_subject() {
+                                            make_subject(room_jid, &topic)
+                                        } else {
+                                            make_reply(room_jid, &topic)
+                                        };
+                                        ash.accounts[account].send(stanza).await;
+                                        continue;
+                                    }
+                                    let directed = addressed.is_some();
+                                    let response = if delayed
+                                        || ash.maintenance
+                                        || room.muted
+                                        || !room.can_speak()
+                                    {
+                                        None
+                                    } else if let Some(body) = addressed {
                                         println!("self body: {body}");
-                                        room.directed_message(
-                                            body,
-                                            &mut brain[room.chain_indices[0]],
-                                        )?
+                                        room.directed_message(&body, &mut ash.brain[chain])?
+                                    } else if room.is_empty() {
+                                        // they already left, nobody would hear us
+                                        None
                                     } else {
-                                        room.non_directed_message(
-                                            body,
-                                            &mut brain[room.chain_indices[0]],
-                                        )?
+                                        room.non_directed_message(body, &mut ash.brain[chain])?
                                     };
                                     if let Some(response) = response {
                                         println!("reply: {}", response);
                                         // todo: reply to from or just node+domain ?
-                                        let from = Jid::Bare(BareJid {
+                                        let room_jid = Jid::Bare(BareJid {
                                             node: Some(node.to_string()),
                                             domain: domain.to_string(),
                                         });
-                                        client
-                                            .send_stanza(make_reply(from.clone(), &response))
-                                            .await?;
+                                        let delay = room.typing_delay(&response);
+                                        if !delay.is_zero() {
+                                            ash.accounts[account]
+                                                .send(make_chat_state(
+                                                    room_jid.clone(),
+                                                    ChatState::Composing,
+                                                ))
+                                                .await;
+                                        }
+                                        let reply = match reply_id(&message, &room_jid) {
+                                            Some(id) if directed && room.threaded_replies => {
+                                                make_threaded_reply(
+                                                    room_jid,
+                                                    &response,
+                                                    from.clone(),
+                                                    &id,
+                                                    body,
+                                                )
+                                            }
+                                            _ => make_reply(room_jid, &response),
+                                        };
+                                        if delay.is_zero() {
+                                            ash.accounts[account].send(reply).await;
+                                        } else {
+                                            let delayed_tx = delayed_tx.clone();
+                                            tokio::spawn(async move {
+                                                tokio::time::sleep(delay).await;
+                                                delayed_tx.send((account, reply)).ok();
+                                            });
+                                        }
+                                    }
+                                    // every persona hears it, but it's only learned once, and stored
+                                    // as learned so rebuilding the chains learns the same thing
+                                    let learned = clean::clean(
+                                        body,
+                                        &clean::attachments(&message),
+                                        &room.clean,
+                                    );
+                                    if learner && !learned.is_empty() {
+                                        let sender = room.sender(resource);
+                                        store_message(
+                                            &ash.conn, node, domain, resource, &learned, &lang,
+                                            &sender,
+                                        )?;
+                                        for x in &chains {
+                                            ash.brain[*x].ingest(&learned);
+                                        }
                                     }
-                                    conn.execute("INSERT INTO msg (node, domain, nick, msg) values (?, ?, ?, ?)", [node, domain, resource, body])?;
-                                    for x in &room.chain_indices {
-                                        brain[*x].ingest(body);
+                                } else if let (MessageType::Chat, Some(command)) =
+                                    (&message.type_, commands::parse_text_command(&body.0))
+                                {
+                                    // one to one messages come straight from the sender's real jid
+                                    let sender = Sender {
+                                        jid: Some(bare(from)),
+                                        ..Default::default()
+                                    };
+                                    let mut stanzas = Vec::new();
+                                    let reply = commands::run_text_command(
+                                        &mut ash,
+                                        command,
+                                        &sender,
+                                        &mut stanzas,
+                                    );
+                                    ash.accounts[account]
+                                        .send(make_chat_reply(from.clone(), &reply))
+                                        .await;
+                                    for stanza in stanzas {
+                                        ash.accounts[account].send(stanza).await;
                                     }
                                 } else {
                                     println!("ignoring: from: '{from}', body: {body:?}");
@@ -273,18 +1703,54 @@ async fn main() -> Result<()> {
         }
     }
 
-    // Close client connection
-    client.send_end().await.ok(); // ignore errors here, I guess
+    // Close client connections
+    for account in &mut ash.accounts {
+        account.connection.send_end().await.ok(); // ignore errors here, I guess
+    }
 
     Ok(())
 }
 
-fn make_join(to: FullJid) -> Element {
+fn make_join(to: FullJid, password: Option<String>) -> Element {
+    make_join_with(to, password, History::new().with_maxstanzas(0))
+}
+
+fn make_join_with(to: FullJid, password: Option<String>, history: History) -> Element {
+    let mut muc = Muc::new().with_history(history);
+    muc.password = password;
     Presence::new(PresenceType::None)
         .with_to(Jid::Full(to))
-        .with_payloads(vec![Muc::new()
-            .with_history(History::new().with_maxstanzas(0))
-            .into()])
+        .with_payloads(vec![muc.into()])
+        .into()
+}
+
+// Construct our <presence/> with our status, to room if given, advertising what we support with
+// XEP-0115 entity caps, busy in maintenance mode and away in muted rooms
+fn make_presence(ash: &Ash, account: usize, room: Option<&Room>) -> Element {
+    let caps = iq::make_caps(&ash.accounts[account]);
+    let mut presence = Presence::new(PresenceType::None).with_payloads(vec![caps]);
+    if let Some(update) = ash.accounts[account]
+        .profile
+        .as_ref()
+        .and_then(Profile::make_update)
+    {
+        presence.payloads.push(update);
+    }
+    presence.to = room.map(|room| Jid::Full(room.jid.clone()));
+    presence.show = if ash.maintenance {
+        Some(Show::Dnd)
+    } else if room.map_or(false, |room| room.muted) {
+        Some(Show::Away)
+    } else {
+        None
+    };
+    presence.statuses.insert(String::new(), ash.status());
+    presence.into()
+}
+
+fn make_leave(to: FullJid) -> Element {
+    Presence::new(PresenceType::Unavailable)
+        .with_to(Jid::Full(to))
         .into()
 }
 
@@ -293,9 +1759,155 @@ fn make_reply(to: Jid, body: &str) -> Element {
     let mut message = Message::new(Some(to));
     message.type_ = MessageType::Groupchat;
     message.bodies.insert(String::new(), Body(body.to_owned()));
+    message.payloads.push(ChatState::Active.into());
     message.into()
 }
 
+// Construct a one to one chat <message/>
+fn make_chat_reply(to: Jid, body: &str) -> Element {
+    let mut message = Message::new(Some(to));
+    message.type_ = MessageType::Chat;
+    message.bodies.insert(String::new(), Body(body.to_owned()));
+    message.into()
+}
+
+// Construct a groupchat <message/> changing the room subject
+fn make_subject(to: Jid, subject: &str) -> Element {
+    let mut message = Message::new(Some(to));
+    message.type_ = MessageType::Groupchat;
+    message
+        .subjects
+        .insert(String::new(), Subject(subject.to_owned()));
+    message.into()
+}
+
+// Construct a groupchat <message/> carrying only a XEP-0085 chat state
+fn make_chat_state(to: Jid, state: ChatState) -> Element {
+    let mut message = Message::new(Some(to));
+    message.type_ = MessageType::Groupchat;
+    message.payloads.push(state.into());
+    message.into()
+}
+
+// Construct a groupchat <message/> answering another one per XEP-0461, with a XEP-0428 fallback
+// quote for clients that don't understand replies
+fn make_threaded_reply(to: Jid, body: &str, in_reply_to: Jid, id: &str, quoted: &str) -> Element {
+    let mut fallback = String::new();
+    for line in quoted.lines() {
+        fallback.push_str("> ");
+        fallback.push_str(line);
+        fallback.push('\n');
+    }
+    // fallback ranges are counted in unicode code points, not bytes
+    let fallback_end = fallback.chars().count();
+
+    let mut message = Message::new(Some(to));
+    message.type_ = MessageType::Groupchat;
+    message
+        .bodies
+        .insert(String::new(), Body(format!("{fallback}{body}")));
+    message.payloads.push(
+        Element::builder("reply", NS_REPLY)
+            .attr("to", in_reply_to.to_string())
+            .attr("id", id)
+            .build(),
+    );
+    message.payloads.push(
+        Element::builder("fallback", NS_FALLBACK)
+            .attr("for", NS_REPLY)
+            .append(
+                Element::builder("body", NS_FALLBACK)
+                    .attr("start", "0")
+                    .attr("end", fallback_end.to_string())
+                    .build(),
+            )
+            .build(),
+    );
+    message.payloads.push(ChatState::Active.into());
+    message.into()
+}
+
+// the id to reference when replying to a groupchat message, the room's stanza-id is preferred
+// because the id the sender chose isn't guaranteed to be unique or even present
+fn reply_id(message: &Message, room: &Jid) -> Option<String> {
+    message
+        .payloads
+        .iter()
+        .filter_map(|payload| StanzaId::try_from(payload.clone()).ok())
+        .find(|stanza_id| &stanza_id.by == room)
+        .map(|stanza_id| stanza_id.id)
+        .or_else(|| message.id.clone())
+}
+
+// if name appears in body as a whole word, case-insensitively and optionally prefixed with @,
+// returns body without it and the punctuation addressing usually comes with
+fn strip_address(body: &str, name: &str) -> Option<String> {
+    body.char_indices()
+        .find_map(|(start, _)| strip_address_at(body, name, start))
+}
+
+// like strip_address, but only when body starts with name, after any whitespace and @
+fn strip_leading_address(body: &str, name: &str) -> Option<String> {
+    let rest = body.trim_start();
+    let rest = rest.strip_prefix('@').unwrap_or(rest);
+    strip_address_at(body, name, body.len() - rest.len())
+}
+
+fn strip_address_at(body: &str, name: &str, start: usize) -> Option<String> {
+    let name = name.to_lowercase();
+    let is_word = |c: char| c.is_alphanumeric() || c == '_' || c == '-';
+    let end = start + name.len();
+    if body.get(start..end)?.to_lowercase() != name {
+        return None;
+    }
+    let before = body[..start].strip_suffix('@').unwrap_or(&body[..start]);
+    let after = &body[end..];
+    if before.chars().next_back().map_or(false, is_word)
+        || after.chars().next().map_or(false, is_word)
+    {
+        return None;
+    }
+    let before = before.trim_end_matches([',', ' ']);
+    let after = after.trim_start_matches([',', ':', ' ']);
+    Some(if before.is_empty() {
+        after.to_string()
+    } else if after.is_empty() {
+        before.to_string()
+    } else {
+        format!("{before} {after}")
+    })
+}
+
+// removes the range [begin, end) counted in unicode code points, as XEP-0372 offsets are
+fn strip_chars(body: &str, begin: usize, end: usize) -> Option<String> {
+    let byte_offset = |idx| {
+        body.char_indices()
+            .map(|(i, _)| i)
+            .chain(std::iter::once(body.len()))
+            .nth(idx)
+    };
+    let (begin, end) = (byte_offset(begin)?, byte_offset(end)?);
+    if begin > end {
+        return None;
+    }
+    let before = body[..begin].trim_end_matches([',', ' ']);
+    let after = body[end..].trim_start_matches([',', ':', ' ']);
+    Some(format!("{before} {after}").trim().to_string())
+}
+
+// 123456 as 123,456
+fn thousands(n: impl ToString) -> String {
+    let digits = n.to_string();
+    let mut grouped = String::with_capacity(digits.len() * 4 / 3);
+    for (i, c) in digits.chars().enumerate() {
+        if i > 0 && (digits.len() - i) % 3 == 0 {
+            grouped.push(',');
+        }
+        grouped.push(c);
+    }
+    grouped
+}
+
 fn should_send(
     body: &str,
     last_sent: &mut Instant,
@@ -322,6 +1934,16 @@ fn chance(pct: f64) -> bool {
     pct > rng.gen_range(0f64..1f64)
 }
 
+// a random factor within pct either side of 1, for making delays look less mechanical
+fn jitter(pct: f64) -> f64 {
+    use rand::Rng;
+    if pct <= 0.0 {
+        return 1.0;
+    }
+    let mut rng = rand::thread_rng();
+    1.0 + rng.gen_range(-pct..pct)
+}
+
 fn choose(choices: &[&str]) -> Option<String> {
     use rand::{prelude::SliceRandom, thread_rng};
     let mut rng = thread_rng();
@@ -1078,3 +2700,132 @@ Because he was a little horse!",
     "What do you call a gorilla wearing headphones? Anything you'd like, it can't hear you.",
     "Have you heard about corduroy pillows?  They're making headlines!"
 ];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn addressed_by_name() {
+        assert_eq!(strip_address("ash: hi", "ash").as_deref(), Some("hi"));
+        assert_eq!(strip_address("ASH, hi", "ash").as_deref(), Some("hi"));
+        assert_eq!(
+            strip_address("hey ash, how are you", "ash").as_deref(),
+            Some("hey how are you")
+        );
+        assert_eq!(
+            strip_address("thanks ash", "ash").as_deref(),
+            Some("thanks")
+        );
+        assert_eq!(
+            strip_address("@ash what's up", "ash").as_deref(),
+            Some("what's up")
+        );
+        assert_eq!(
+            strip_address("ask the bot: why", "the bot").as_deref(),
+            Some("ask why")
+        );
+    }
+
+    #[test]
+    fn not_addressed_inside_words() {
+        assert_eq!(strip_address("ashley: hi", "ash"), None);
+        assert_eq!(strip_address("that's trash", "ash"), None);
+        assert_eq!(strip_address("ash_bot: hi", "ash"), None);
+        assert_eq!(strip_address("hi there", "ash"), None);
+    }
+
+    #[test]
+    fn addressed_with_non_ascii_nicks() {
+        assert_eq!(strip_address("ZOË: salut", "Zoë").as_deref(), Some("salut"));
+        assert_eq!(
+            strip_address("héhé zoë, ça va", "zoë").as_deref(),
+            Some("héhé ça va")
+        );
+        assert_eq!(strip_address("zoëlle: salut", "zoë"), None);
+        assert_eq!(strip_address("アッシュくん: こんにちは", "アッシュ"), None);
+        assert_eq!(
+            strip_address("アッシュ: こんにちは", "アッシュ").as_deref(),
+            Some("こんにちは")
+        );
+    }
+
+    #[test]
+    fn commands_only_when_addressed_first() {
+        assert_eq!(
+            strip_leading_address("ash: mute", "ash").as_deref(),
+            Some("mute")
+        );
+        assert_eq!(
+            strip_leading_address(" @Ash leave", "ash").as_deref(),
+            Some("leave")
+        );
+        assert_eq!(strip_leading_address("mute ash", "ash"), None);
+        assert_eq!(strip_leading_address("don't mute ash", "ash"), None);
+        assert_eq!(strip_leading_address("ashley: mute", "ash"), None);
+    }
+
+    #[test]
+    fn text_commands() {
+        use commands::{parse_text_command, TextCommand};
+        assert!(matches!(
+            parse_text_command("leave"),
+            Some(TextCommand::Leave(None))
+        ));
+        assert!(matches!(
+            parse_text_command("MUTE room@example.org"),
+            Some(TextCommand::Mute(Some(room), true)) if room.to_string() == "room@example.org"
+        ));
+        assert!(matches!(
+            parse_text_command("unmute"),
+            Some(TextCommand::Mute(None, false))
+        ));
+        assert!(matches!(
+            parse_text_command("maintenance off"),
+            Some(TextCommand::Maintenance(false))
+        ));
+        assert!(matches!(
+            parse_text_command("unlearn some text"),
+            Some(TextCommand::Unlearn(text)) if text == "some text"
+        ));
+        assert!(matches!(
+            parse_text_command("forget me"),
+            Some(TextCommand::ForgetMe)
+        ));
+        match parse_text_command("join room@example.org 1 2") {
+            Some(TextCommand::Join(cfg)) => {
+                assert_eq!(cfg.room, "room@example.org");
+                assert_eq!(cfg.chain_indices, Some(vec![1, 2]));
+            }
+            _ => panic!("not a join"),
+        }
+    }
+
+    #[test]
+    fn chat_isnt_a_command() {
+        use commands::parse_text_command;
+        assert!(parse_text_command("join us").is_none());
+        assert!(parse_text_command("leave me alone").is_none());
+        assert!(parse_text_command("mute the tv").is_none());
+        assert!(parse_text_command("maintenance please").is_none());
+        assert!(parse_text_command("forget it").is_none());
+        assert!(parse_text_command("unlearn").is_none());
+        assert!(parse_text_command("hello there").is_none());
+    }
+
+    #[test]
+    fn reference_offsets() {
+        // counted in code points, ë is two bytes
+        assert_eq!(strip_chars("Zoë: hi", 0, 3).as_deref(), Some("hi"));
+        assert_eq!(strip_chars("hey zoë how", 4, 7).as_deref(), Some("hey how"));
+        assert_eq!(strip_chars("hi ash", 3, 6).as_deref(), Some("hi"));
+    }
+
+    #[test]
+    fn reference_offsets_out_of_range() {
+        assert_eq!(strip_chars("ash: hi", 0, 8), None);
+        assert_eq!(strip_chars("ash: hi", 8, 9), None);
+        assert_eq!(strip_chars("ash: hi", 3, 1), None);
+        assert_eq!(strip_chars("Zoë", 0, 4), None);
+    }
+}
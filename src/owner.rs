@@ -0,0 +1,54 @@
+use crate::CreateConfig;
+use xmpp_parsers::{
+    iq::{Iq, IqType},
+    ns, BareJid, Element, Jid,
+};
+
+const NS_MUC_OWNER: &str = "http://jabber.org/protocol/muc#owner";
+const NS_ROOMCONFIG: &str = "http://jabber.org/protocol/muc#roomconfig";
+
+// id of the <iq/>s configuring rooms we created, so failures can be told apart
+pub const CONFIG_ID: &str = "ash-room-config";
+
+// Construct the XEP-0045 owner form unlocking a room we just created, with whatever create sets,
+// or accepting the server's defaults as an instant room when it's None
+pub fn make_config(room: BareJid, create: Option<&CreateConfig>) -> Element {
+    let mut form = Element::builder("x", ns::DATA_FORMS).attr("type", "submit");
+    if let Some(create) = create {
+        let flag = |set: bool| if set { "1" } else { "0" }.to_string();
+        let fields = [
+            ("FORM_TYPE", Some(NS_ROOMCONFIG.to_string())),
+            ("muc#roomconfig_roomname", create.name.clone()),
+            ("muc#roomconfig_roomdesc", create.description.clone()),
+            ("muc#roomconfig_persistentroom", create.persistent.map(flag)),
+            ("muc#roomconfig_membersonly", create.members_only.map(flag)),
+        ];
+        for (var, value) in fields {
+            if let Some(value) = value {
+                form = form.append(field(var, &value));
+            }
+        }
+    }
+    Iq {
+        from: None,
+        to: Some(Jid::Bare(room)),
+        id: CONFIG_ID.to_string(),
+        payload: IqType::Set(
+            Element::builder("query", NS_MUC_OWNER)
+                .append(form.build())
+                .build(),
+        ),
+    }
+    .into()
+}
+
+fn field(var: &str, value: &str) -> Element {
+    Element::builder("field", ns::DATA_FORMS)
+        .attr("var", var)
+        .append(
+            Element::builder("value", ns::DATA_FORMS)
+                .append(value)
+                .build(),
+        )
+        .build()
+}
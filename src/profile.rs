@@ -0,0 +1,321 @@
+use crate::{bare, Ash, ProfileConfig};
+use anyhow::{bail, Result};
+use sha1::{Digest, Sha1};
+use std::{collections::HashMap, fs, path::Path};
+use xmpp_parsers::{
+    iq::{Iq, IqType},
+    ns, Element, Jid,
+};
+
+const NS_AVATAR_DATA: &str = "urn:xmpp:avatar:data";
+const NS_AVATAR_METADATA: &str = "urn:xmpp:avatar:metadata";
+const NS_VCARD_TEMP: &str = "vcard-temp";
+const NS_VCARD_UPDATE: &str = "vcard-temp:x:update";
+const NS_VCARD4: &str = "urn:ietf:params:xml:ns:vcard-4.0";
+const NODE_VCARD4: &str = "urn:xmpp:vcard4";
+const NS_PUBLISH_OPTIONS: &str = "http://jabber.org/protocol/pubsub#publish-options";
+
+// what each <iq/> fetching our profile as the server has it asks for, prefixed to an id that
+// can't be guessed so the results can be told apart from other responses
+const AVATAR_ID: &str = "ash-avatar";
+const VCARD_TEMP_ID: &str = "ash-vcard-temp";
+const VCARD4_ID: &str = "ash-vcard4";
+// id of the ones changing it
+pub const PUBLISH_ID: &str = "ash-profile-publish";
+
+// how ash presents itself to everyone else
+pub struct Profile {
+    avatar: Option<Avatar>,
+    name: Option<String>,
+    url: Option<String>,
+    description: Option<String>,
+    // ids of the requests from make_requests still waiting for a response, to what they ask for
+    requests: HashMap<String, &'static str>,
+}
+
+struct Avatar {
+    data: Vec<u8>,
+    // hex SHA-1 of data, which XEP-0084 uses to identify it
+    id: String,
+    mime: &'static str,
+}
+
+impl Profile {
+    pub fn new(cfg: &ProfileConfig) -> Result<Self> {
+        let avatar = match &cfg.avatar {
+            Some(path) => {
+                let extension = Path::new(path)
+                    .extension()
+                    .and_then(|e| e.to_str())
+                    .map(|e| e.to_lowercase());
+                let mime = match extension.as_deref() {
+                    Some("png") => "image/png",
+                    Some("jpg") | Some("jpeg") => "image/jpeg",
+                    Some("gif") => "image/gif",
+                    Some("webp") => "image/webp",
+                    _ => bail!("avatar must be a png, jpeg, gif or webp image: {path}"),
+                };
+                let data = fs::read(path)?;
+                Some(Avatar {
+                    id: format!("{:x}", Sha1::digest(&data)),
+                    data,
+                    mime,
+                })
+            }
+            None => None,
+        };
+        Ok(Self {
+            avatar,
+            name: cfg.name.clone(),
+            url: cfg.url.clone(),
+            description: cfg.description.clone(),
+            requests: HashMap::new(),
+        })
+    }
+
+    // Construct the XEP-0153 hash of our avatar that goes in every presence, which is how rooms
+    // show it, since clients can't look up avatars on real jids they can't see
+    pub fn make_update(&self) -> Option<Element> {
+        let avatar = self.avatar.as_ref()?;
+        Some(
+            Element::builder("x", NS_VCARD_UPDATE)
+                .append(text("photo", NS_VCARD_UPDATE, &avatar.id))
+                .build(),
+        )
+    }
+
+    fn has_vcard(&self) -> bool {
+        self.name.is_some() || self.url.is_some() || self.description.is_some()
+    }
+
+    // Construct the <iq/>s fetching what the server has now, handle_response publishes ours in
+    // place of anything that differs
+    pub fn make_requests(&mut self) -> Vec<Element> {
+        // anything still unanswered was lost with the previous connection
+        self.requests.clear();
+        let mut requests = Vec::new();
+        if self.avatar.is_some() {
+            requests.push(self.make_request(AVATAR_ID, items(NS_AVATAR_METADATA)));
+        }
+        // older clients only know avatars from vcard-temp
+        if self.avatar.is_some() || self.has_vcard() {
+            requests.push(self.make_request(
+                VCARD_TEMP_ID,
+                Element::builder("vCard", NS_VCARD_TEMP).build(),
+            ));
+        }
+        if self.has_vcard() {
+            requests.push(self.make_request(VCARD4_ID, items(NODE_VCARD4)));
+        }
+        requests
+    }
+
+    fn make_request(&mut self, kind: &'static str, payload: Element) -> Element {
+        use rand::Rng;
+        let id = format!("{kind}-{:016x}", rand::thread_rng().gen::<u64>());
+        self.requests.insert(id.clone(), kind);
+        make_iq(&id, IqType::Get(payload))
+    }
+
+    // what the server has, current is None when it has nothing at all, returns what to publish
+    pub fn handle_response(&mut self, id: &str, current: Option<&Element>) -> Vec<Element> {
+        match self.requests.remove(id).unwrap_or_default() {
+            AVATAR_ID => {
+                let avatar = match &self.avatar {
+                    Some(avatar) => avatar,
+                    None => return vec![],
+                };
+                let current = current
+                    .and_then(first_item)
+                    .and_then(|item| item.attr("id"));
+                if current == Some(avatar.id.as_str()) {
+                    return vec![];
+                }
+                println!("publishing avatar {}", avatar.id);
+                // metadata last, since clients fetch the data as soon as they see it
+                vec![
+                    publish(
+                        NS_AVATAR_DATA,
+                        &avatar.id,
+                        Element::builder("data", NS_AVATAR_DATA)
+                            .append(base64::encode(&avatar.data))
+                            .build(),
+                    ),
+                    publish(
+                        NS_AVATAR_METADATA,
+                        &avatar.id,
+                        Element::builder("metadata", NS_AVATAR_METADATA)
+                            .append(
+                                Element::builder("info", NS_AVATAR_METADATA)
+                                    .attr("bytes", avatar.data.len().to_string())
+                                    .attr("id", avatar.id.as_str())
+                                    .attr("type", avatar.mime)
+                                    .build(),
+                            )
+                            .build(),
+                    ),
+                ]
+            }
+            VCARD_TEMP_ID => {
+                let ours = self.vcard_temp();
+                if current.map_or(false, |current| contains(current, &ours)) {
+                    return vec![];
+                }
+                println!("publishing vcard-temp");
+                vec![make_iq(PUBLISH_ID, IqType::Set(ours))]
+            }
+            VCARD4_ID => {
+                let ours = self.vcard4();
+                let current = current
+                    .and_then(first_item)
+                    .and_then(|item| item.get_child("vcard", NS_VCARD4));
+                if current.map_or(false, |current| contains(current, &ours)) {
+                    return vec![];
+                }
+                println!("publishing vcard4");
+                vec![publish(NODE_VCARD4, "current", ours)]
+            }
+            _ => vec![],
+        }
+    }
+
+    fn vcard_temp(&self) -> Element {
+        let photo = self.avatar.as_ref().map(|avatar| {
+            Element::builder("PHOTO", NS_VCARD_TEMP)
+                .append(text("TYPE", NS_VCARD_TEMP, avatar.mime))
+                .append(text("BINVAL", NS_VCARD_TEMP, &base64::encode(&avatar.data)))
+                .build()
+        });
+        Element::builder("vCard", NS_VCARD_TEMP)
+            .append_all(self.name.as_ref().map(|n| text("FN", NS_VCARD_TEMP, n)))
+            .append_all(self.url.as_ref().map(|u| text("URL", NS_VCARD_TEMP, u)))
+            .append_all(
+                self.description
+                    .as_ref()
+                    .map(|d| text("DESC", NS_VCARD_TEMP, d)),
+            )
+            .append_all(photo)
+            .build()
+    }
+
+    fn vcard4(&self) -> Element {
+        // every vCard 4 property wraps its value in an element saying what type it is
+        let property = |name: &str, type_: &str, value: &String| {
+            Element::builder(name, NS_VCARD4)
+                .append(text(type_, NS_VCARD4, value))
+                .build()
+        };
+        Element::builder("vcard", NS_VCARD4)
+            .append_all(self.name.as_ref().map(|n| property("fn", "text", n)))
+            .append_all(self.url.as_ref().map(|u| property("url", "uri", u)))
+            .append_all(
+                self.description
+                    .as_ref()
+                    .map(|d| property("note", "text", d)),
+            )
+            .build()
+    }
+}
+
+// whether an <iq/> response with id from from answers make_requests, which only our own account can
+pub fn is_response(ash: &Ash, id: &str, from: Option<&Jid>) -> bool {
+    ash.account()
+        .profile
+        .as_ref()
+        .map_or(false, |profile| profile.requests.contains_key(id))
+        && from.map_or(true, |from| bare(from) == ash.account().jid)
+}
+
+fn make_iq(id: &str, payload: IqType) -> Element {
+    Iq {
+        from: None,
+        to: None,
+        id: id.to_string(),
+        payload,
+    }
+    .into()
+}
+
+// a request for the latest item of a node on our own PEP service
+fn items(node: &str) -> Element {
+    Element::builder("pubsub", ns::PUBSUB)
+        .append(
+            Element::builder("items", ns::PUBSUB)
+                .attr("node", node)
+                .attr("max_items", "1")
+                .build(),
+        )
+        .build()
+}
+
+fn first_item(pubsub: &Element) -> Option<&Element> {
+    pubsub
+        .get_child("items", ns::PUBSUB)?
+        .children()
+        .find(|item| item.is("item", ns::PUBSUB))
+}
+
+// Construct an <iq/> publishing payload to our own PEP service, readable by anyone rather than
+// only our contacts, so everyone in our rooms can see it
+fn publish(node: &str, id: &str, payload: Element) -> Element {
+    let options = Element::builder("x", ns::DATA_FORMS)
+        .attr("type", "submit")
+        .append(
+            Element::builder("field", ns::DATA_FORMS)
+                .attr("var", "FORM_TYPE")
+                .attr("type", "hidden")
+                .append(text("value", ns::DATA_FORMS, NS_PUBLISH_OPTIONS))
+                .build(),
+        )
+        .append(
+            Element::builder("field", ns::DATA_FORMS)
+                .attr("var", "pubsub#access_model")
+                .append(text("value", ns::DATA_FORMS, "open"))
+                .build(),
+        )
+        .build();
+    make_iq(
+        PUBLISH_ID,
+        IqType::Set(
+            Element::builder("pubsub", ns::PUBSUB)
+                .append(
+                    Element::builder("publish", ns::PUBSUB)
+                        .attr("node", node)
+                        .append(
+                            Element::builder("item", ns::PUBSUB)
+                                .attr("id", id)
+                                .append(payload)
+                                .build(),
+                        )
+                        .build(),
+                )
+                .append(
+                    Element::builder("publish-options", ns::PUBSUB)
+                        .append(options)
+                        .build(),
+                )
+                .build(),
+        ),
+    )
+}
+
+fn text(name: &str, ns: &str, text: &str) -> Element {
+    Element::builder(name, ns).append(text).build()
+}
+
+// whether current has everything ours does, servers are free to add fields of their own and
+// reformat values, base64 especially
+fn contains(current: &Element, ours: &Element) -> bool {
+    let squash = |text: String| text.split_whitespace().collect::<String>();
+    ours.children().all(|ours| {
+        current.children().any(|current| {
+            current.name() == ours.name()
+                && current.ns() == ours.ns()
+                && if ours.children().next().is_none() {
+                    squash(current.text()) == squash(ours.text())
+                } else {
+                    contains(current, ours)
+                }
+        })
+    })
+}
@@ -0,0 +1,199 @@
+use crate::{
+    login::{self, query, Login},
+    AccountConfig, Config,
+};
+use anyhow::{bail, Result};
+use futures::sink::SinkExt;
+use rand::{distributions::Alphanumeric, Rng};
+use std::{fs, path::Path};
+use tokio_xmpp::Packet;
+use xmpp_parsers::{iq::IqType, ns, stanza_error::DefinedCondition, BareJid, Element};
+
+const NS_REGISTER: &str = "jabber:iq:register";
+const NS_CAPTCHA: &str = "urn:xmpp:captcha";
+
+// creates every client account in cfg with XEP-0077 in-band registration, the first one gets a
+// password made up for it if it doesn't have one yet, which is written into the config at path
+pub async fn register(path: &Path, cfg: &Config) -> Result<()> {
+    let mut registered = 0;
+    if cfg.account.jid.is_some() {
+        match &cfg.account.password {
+            Some(password) => register_account(&cfg.account, password).await?,
+            None => {
+                let password: String = rand::thread_rng()
+                    .sample_iter(&Alphanumeric)
+                    .take(24)
+                    .map(char::from)
+                    .collect();
+                register_account(&cfg.account, &password).await?;
+                if let Err(e) = save_password(path, &password) {
+                    bail!("registered with password {password}, but couldn't save it: {e}");
+                }
+                println!("password saved to {}", path.display());
+            }
+        }
+        registered += 1;
+    }
+    for account in &cfg.accounts {
+        // components are set up on the server, not registered
+        if account.jid.is_none() {
+            continue;
+        }
+        match &account.password {
+            Some(password) => register_account(account, password).await?,
+            None => bail!(
+                "set a password for {} to register it with",
+                account.jid.as_deref().unwrap_or_default()
+            ),
+        }
+        registered += 1;
+    }
+    if registered == 0 {
+        bail!("no jid to register, components can't be");
+    }
+    Ok(())
+}
+
+async fn register_account(cfg: &AccountConfig, password: &str) -> Result<()> {
+    let jid: BareJid = cfg.jid.as_deref().unwrap_or_default().parse()?;
+    let username = match &jid.node {
+        Some(node) => node.clone(),
+        None => bail!("{jid} has no username to register"),
+    };
+    // the password goes over this, so with the same TLS settings as logging in
+    let login = Login::new(&jid, None, password, cfg.connection.as_ref())?;
+    println!("registering {jid}");
+    let mut stream = login::connect(&login).await?;
+
+    let fields = match query(
+        &mut stream,
+        "ash-register-fields",
+        IqType::Get(Element::builder("query", NS_REGISTER).build()),
+    )
+    .await?
+    {
+        IqType::Result(Some(fields)) => fields,
+        IqType::Error(error) => bail!(
+            "{} doesn't allow registration: {:?}",
+            jid.domain,
+            error.defined_condition
+        ),
+        _ => bail!("{} doesn't allow registration", jid.domain),
+    };
+    // servers offering a form expect it filled in, over the fields alongside it
+    let submission = match fields.get_child("x", ns::DATA_FORMS) {
+        Some(form) => vec![fill_form(form, &username, password)?],
+        None => fill_fields(&fields, &username, password)?,
+    };
+    let result = query(
+        &mut stream,
+        "ash-register",
+        IqType::Set(
+            Element::builder("query", NS_REGISTER)
+                .append_all(submission)
+                .build(),
+        ),
+    )
+    .await?;
+    stream.send(Packet::StreamEnd).await.ok();
+    match result {
+        IqType::Result(_) => {
+            println!("registered {jid}");
+            Ok(())
+        }
+        IqType::Error(error) => match error.defined_condition {
+            DefinedCondition::Conflict => bail!("{jid} is already taken"),
+            DefinedCondition::NotAcceptable => {
+                bail!("{} wants more than a username and password", jid.domain)
+            }
+            condition => bail!("failed to register {jid}: {condition:?}"),
+        },
+        _ => bail!("failed to register {jid}"),
+    }
+}
+
+// the plain fields of the registration query, which can't be anything besides username and
+// password for ash to be able to fill them in
+fn fill_fields(fields: &Element, username: &str, password: &str) -> Result<Vec<Element>> {
+    let mut submission = Vec::new();
+    for field in fields.children() {
+        let value = match field.name() {
+            "username" => username,
+            "password" => password,
+            "instructions" => continue,
+            name => bail!("the server also wants {name}, register by hand instead"),
+        };
+        submission.push(
+            Element::builder(field.name(), NS_REGISTER)
+                .append(value)
+                .build(),
+        );
+    }
+    Ok(submission)
+}
+
+// the registration form filled in, which can have anything besides username and password as
+// long as it's not required, CAPTCHAs especially can't be
+fn fill_form(form: &Element, username: &str, password: &str) -> Result<Element> {
+    let mut submission = Element::builder("x", ns::DATA_FORMS).attr("type", "submit");
+    for field in form.children().filter(|f| f.is("field", ns::DATA_FORMS)) {
+        let var = match field.attr("var") {
+            Some(var) => var,
+            None => continue,
+        };
+        let value = match (var, field.attr("type")) {
+            ("username", _) => username.to_string(),
+            ("password", _) => password.to_string(),
+            // FORM_TYPE and anything else the server needs back, like a challenge id
+            (_, Some("hidden")) => {
+                let value = field
+                    .get_child("value", ns::DATA_FORMS)
+                    .map(|value| value.text())
+                    .unwrap_or_default();
+                if var == "FORM_TYPE" && value == NS_CAPTCHA {
+                    bail!("the server wants a CAPTCHA solved, register by hand instead");
+                }
+                value
+            }
+            _ if field.has_child("required", ns::DATA_FORMS) => {
+                bail!("the server also wants {var}, register by hand instead")
+            }
+            _ => continue,
+        };
+        submission = submission.append(
+            Element::builder("field", ns::DATA_FORMS)
+                .attr("var", var)
+                .append(
+                    Element::builder("value", ns::DATA_FORMS)
+                        .append(value)
+                        .build(),
+                )
+                .build(),
+        );
+    }
+    Ok(submission.build())
+}
+
+// adds password right after the first jid in the config file, editing the text rather than
+// writing the config out again so comments and layout are kept
+fn save_password(path: &Path, password: &str) -> Result<()> {
+    let config = fs::read_to_string(path)?;
+    let mut saved = false;
+    let mut out = String::with_capacity(config.len() + password.len() + 16);
+    for line in config.lines() {
+        out.push_str(line);
+        out.push('\n');
+        if !saved && line.split('=').next().map(str::trim) == Some("jid") && line.contains('=') {
+            out.push_str(&format!("password = \"{password}\"\n"));
+            saved = true;
+        }
+    }
+    if !saved {
+        bail!(
+            "couldn't find jid in {} to add the password after",
+            path.display()
+        );
+    }
+    fs::write(path, out)?;
+    Ok(())
+}
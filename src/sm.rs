@@ -0,0 +1,244 @@
+use std::collections::VecDeque;
+use xmpp_parsers::{ns, Element};
+
+pub const NS_SM: &str = "urn:xmpp:sm:3";
+
+// what a login ended up with
+pub enum Session {
+    // a new session, and whether the server offers stream management on it
+    New(bool),
+    // the last session carried on, the server having got h of the stanzas sent on it
+    Resumed(u32),
+}
+
+// the session to resume on logging in again, and how many of its stanzas we got
+#[derive(Clone)]
+pub struct Resumption {
+    pub id: String,
+    pub h: u32,
+}
+
+// XEP-0198 acknowledgements and resumption, which tokio-xmpp doesn't do itself, so accounts that
+// want it log in through login.rs. When a session can't be resumed, messages it never acknowledged
+// are sent again on the new one, which duplicates any the server got but hadn't acknowledged yet.
+#[derive(Default)]
+pub struct StreamManagement {
+    pub wanted: bool,
+    // from sending <enable/> until the server refuses it or the stream goes away, the server
+    // counts what we send from when it gets <enable/>
+    enabled: bool,
+    // what the server calls the session in <enabled/>, when it can be resumed
+    id: Option<String>,
+    // from the server's <enabled/>, which is when it starts expecting us to count what it sends
+    counting: bool,
+    // stanzas received since <enabled/>, which is what <a/> reports
+    inbound: u32,
+    // stanzas sent since enabling
+    outbound: u32,
+    // messages sent but not yet acknowledged, oldest first, with the value of outbound once each
+    // was sent
+    unacked: VecDeque<(u32, Element)>,
+}
+
+impl StreamManagement {
+    pub fn new(wanted: bool) -> Self {
+        Self {
+            wanted,
+            ..Default::default()
+        }
+    }
+
+    // on a new session, returns <enable/> and anything the last session never acknowledged, which
+    // should go through sent again once rooms have been rejoined, None if it isn't wanted or the
+    // server doesn't offer it, since servers may close the stream when sent <enable/> regardless
+    pub fn start(&mut self, offered: bool) -> Option<(Element, Vec<Element>)> {
+        self.enabled = false;
+        self.id = None;
+        self.counting = false;
+        self.inbound = 0;
+        self.outbound = 0;
+        if !self.wanted {
+            return None;
+        }
+        if !offered {
+            println!("server doesn't offer stream management");
+            self.unacked.clear();
+            return None;
+        }
+        self.enabled = true;
+        Some((
+            Element::builder("enable", NS_SM)
+                .attr("resume", "true")
+                .build(),
+            self.take_unacked(),
+        ))
+    }
+
+    // on the last session being resumed, after the server got h of what we sent, returns what it
+    // didn't get, which should go through sent again
+    pub fn resume(&mut self, h: u32) -> Vec<Element> {
+        self.acknowledge(h);
+        // everything after h was lost with the connection, including what isn't kept to send again
+        self.outbound = h;
+        self.take_unacked()
+    }
+
+    // how to resume the session after losing the connection, None if it can't be
+    pub fn resumption(&self) -> Option<Resumption> {
+        Some(Resumption {
+            id: self.id.clone()?,
+            h: self.inbound,
+        })
+    }
+
+    fn take_unacked(&mut self) -> Vec<Element> {
+        let unacked: Vec<_> = std::mem::take(&mut self.unacked)
+            .into_iter()
+            .map(|(_, stanza)| stanza)
+            .collect();
+        if !unacked.is_empty() {
+            println!("sending {} unacknowledged messages again", unacked.len());
+        }
+        unacked
+    }
+
+    // forgets every message the server says it got, h being how many stanzas it has
+    fn acknowledge(&mut self, h: u32) {
+        // h wraps around at 2^32, so compare by distance rather than value
+        while let Some((sent, _)) = self.unacked.front() {
+            if h.wrapping_sub(*sent) > u32::MAX / 2 {
+                break;
+            }
+            self.unacked.pop_front();
+        }
+    }
+
+    // counts stanza as sent, returns a request for the server to acknowledge it if it's a message
+    pub fn sent(&mut self, stanza: &Element) -> Option<Element> {
+        if !self.enabled || !is_stanza(stanza) {
+            return None;
+        }
+        self.outbound = self.outbound.wrapping_add(1);
+        if !stanza.is("message", ns::DEFAULT_NS) {
+            return None;
+        }
+        self.unacked.push_back((self.outbound, stanza.clone()));
+        Some(Element::builder("r", NS_SM).build())
+    }
+
+    // counts element if it's a stanza, or handles it if it's one of ours, returning what to answer
+    // it with, None means it's for someone else to handle
+    pub fn received(&mut self, element: &Element) -> Option<Vec<Element>> {
+        if element.ns() != NS_SM {
+            if self.counting && is_stanza(element) {
+                self.inbound = self.inbound.wrapping_add(1);
+            }
+            return None;
+        }
+        match element.name() {
+            "enabled" => {
+                let resumable = matches!(element.attr("resume"), Some("true") | Some("1"));
+                self.id = element.attr("id").filter(|_| resumable).map(String::from);
+                match self.id {
+                    Some(_) => println!("stream management enabled, with resumption"),
+                    None => println!("stream management enabled, without resumption"),
+                }
+                self.counting = true;
+            }
+            "failed" => {
+                println!("server refused stream management, unacknowledged messages are lost");
+                self.enabled = false;
+                self.id = None;
+                self.counting = false;
+                self.unacked.clear();
+            }
+            "r" => {
+                return Some(vec![Element::builder("a", NS_SM)
+                    .attr("h", self.inbound.to_string())
+                    .build()])
+            }
+            "a" => {
+                if let Some(h) = element.attr("h").and_then(|h| h.parse::<u32>().ok()) {
+                    self.acknowledge(h);
+                }
+            }
+            _ => {}
+        }
+        Some(vec![])
+    }
+
+    pub fn unacked(&self) -> usize {
+        self.unacked.len()
+    }
+}
+
+// only these are counted, stream management's own elements aren't
+fn is_stanza(element: &Element) -> bool {
+    element.ns() == ns::DEFAULT_NS && matches!(element.name(), "message" | "presence" | "iq")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(body: &str) -> Element {
+        Element::builder("message", ns::DEFAULT_NS)
+            .append(
+                Element::builder("body", ns::DEFAULT_NS)
+                    .append(body.to_string())
+                    .build(),
+            )
+            .build()
+    }
+
+    fn enabled(sm: &mut StreamManagement, id: Option<&str>) {
+        let mut enabled = Element::builder("enabled", NS_SM);
+        if let Some(id) = id {
+            enabled = enabled.attr("id", id).attr("resume", "true");
+        }
+        assert!(sm.start(true).is_some());
+        sm.received(&enabled.build());
+    }
+
+    #[test]
+    fn not_enabled_unless_offered() {
+        let mut sm = StreamManagement::new(true);
+        assert!(sm.start(false).is_none());
+        assert!(sm.sent(&message("hi")).is_none());
+        assert!(StreamManagement::new(false).start(true).is_none());
+    }
+
+    #[test]
+    fn resumes_after_what_the_server_got() {
+        let mut sm = StreamManagement::new(true);
+        enabled(&mut sm, Some("session"));
+        sm.received(&message("hello"));
+        for body in ["one", "two", "three"] {
+            assert!(sm.sent(&message(body)).is_some());
+        }
+        let resumption = sm.resumption().unwrap();
+        assert_eq!((resumption.id.as_str(), resumption.h), ("session", 1));
+        let unacked = sm.resume(1);
+        let bodies: Vec<_> = unacked
+            .iter()
+            .map(|m| m.get_child("body", ns::DEFAULT_NS).unwrap().text())
+            .collect();
+        assert_eq!(bodies, ["two", "three"]);
+        // sent again, and counted from where the server is
+        for message in &unacked {
+            sm.sent(message);
+        }
+        sm.received(&Element::builder("a", NS_SM).attr("h", "3").build());
+        assert_eq!(sm.unacked(), 0);
+    }
+
+    #[test]
+    fn only_resumable_when_the_server_says_so() {
+        let mut sm = StreamManagement::new(true);
+        enabled(&mut sm, None);
+        assert!(sm.resumption().is_none());
+        enabled(&mut sm, Some("session"));
+        sm.received(&Element::builder("failed", NS_SM).build());
+        assert!(sm.resumption().is_none());
+    }
+}